@@ -1,8 +1,208 @@
 use std::any::Any;
 use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// how safe for work an entry is, as far as its source knows
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rating {
+    Safe,
+    Questionable,
+    Explicit,
+    #[default]
+    Unknown,
+}
+
+impl Rating {
+    /// reads the ratings boorus use, both spelled out and as their first letter
+    pub fn parse(s: &str) -> Self {
+        match s.trim().to_lowercase().as_str() {
+            "s" | "safe" | "g" | "general" => Rating::Safe,
+            // sensitive is newer gelbooru speak for mildly lewd
+            "q" | "questionable" | "sensitive" => Rating::Questionable,
+            "e" | "explicit" => Rating::Explicit,
+            _ => Rating::Unknown,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Rating::Safe => "safe",
+            Rating::Questionable => "questionable",
+            Rating::Explicit => "explicit",
+            Rating::Unknown => "unknown",
+        }
+    }
+}
+
+/// what kind of thing a tag describes, as boorus group them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TagCategory {
+    Artist,
+    Character,
+    Copyright,
+    General,
+    Meta,
+    Species,
+}
+
+impl TagCategory {
+    pub const ALL: [TagCategory; 6] = [
+        TagCategory::Artist,
+        TagCategory::Character,
+        TagCategory::Copyright,
+        TagCategory::General,
+        TagCategory::Meta,
+        TagCategory::Species,
+    ];
+
+    /// reads the tag type names boorus use
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "artist" => Some(TagCategory::Artist),
+            "character" => Some(TagCategory::Character),
+            "copyright" => Some(TagCategory::Copyright),
+            "general" => Some(TagCategory::General),
+            "meta" | "metadata" => Some(TagCategory::Meta),
+            "species" => Some(TagCategory::Species),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TagCategory::Artist => "artist",
+            TagCategory::Character => "character",
+            TagCategory::Copyright => "copyright",
+            TagCategory::General => "general",
+            TagCategory::Meta => "meta",
+            TagCategory::Species => "species",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaKind {
+    Image,
+    // gifs and the like
+    Animation,
+    Video,
+    Audio,
+    Other,
+}
+
+impl MediaKind {
+    /// a guess going by the extension of `url`
+    pub fn guess(url: &str) -> Self {
+        let name = url.split('?').next().unwrap_or(url);
+        let ext = name.rsplit('.').next().unwrap_or("").to_lowercase();
+
+        match ext.as_str() {
+            "jpg" | "jpeg" | "png" | "webp" | "avif" | "bmp" => MediaKind::Image,
+            "gif" | "apng" => MediaKind::Animation,
+            "mp4" | "webm" | "mov" | "mkv" => MediaKind::Video,
+            "mp3" | "ogg" | "flac" | "wav" | "m4a" => MediaKind::Audio,
+            _ => MediaKind::Other,
+        }
+    }
+}
+
+/// one file of an entry, posts can have several, e.g. galleries
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Media {
+    pub url: String,
+    pub kind: MediaKind,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+
+    // what the source calls the file, if not the last part of the url
+    pub filename: Option<String>,
+
+    // filled in once an output saved it
+    pub local_file: Option<PathBuf>,
+    pub media_type: Option<String>,
+}
+
+impl Media {
+    pub fn new(url: impl Into<String>) -> Self {
+        let url = url.into();
+
+        Self {
+            kind: MediaKind::guess(&url),
+            url,
+            width: None,
+            height: None,
+            filename: None,
+            local_file: None,
+            media_type: None,
+        }
+    }
+
+    pub fn with_size(mut self, width: u32, height: u32) -> Self {
+        self.width = Some(width);
+        self.height = Some(height);
+        self
+    }
+
+    pub fn with_filename(mut self, filename: impl Into<String>) -> Self {
+        self.filename = Some(filename.into());
+        self
+    }
+
+    /// the filename hint, or the last part of the url
+    pub fn filename(&self) -> String {
+        match &self.filename {
+            Some(name) => name.clone(),
+            None => self
+                .url
+                .rsplit('/')
+                .next()
+                .and_then(|name| name.split('?').next())
+                .unwrap_or_default()
+                .to_owned(),
+        }
+    }
+}
 
 pub trait Entry: Any + Debug + Send + Sync {
+    /// name of the source that produced this entry, e.g. "gelbooru"
+    fn source_name(&self) -> Option<String> {
+        None
+    }
+
+    /// the tag or query of the source this entry was found through
+    fn source_tag(&self) -> Option<String> {
+        None
+    }
+
+    /// the id of this entry on its source
+    fn id(&self) -> Option<String> {
+        None
+    }
+
+    /// md5 of the content, if the source tells us
+    fn md5(&self) -> Option<String> {
+        None
+    }
+
+    fn artist(&self) -> Option<String> {
+        None
+    }
+
+    fn characters(&self) -> Option<&HashSet<String>> {
+        None
+    }
+
+    fn rating(&self) -> Rating {
+        Rating::Unknown
+    }
+
     fn source_url(&self) -> Option<String> {
         None
     }
@@ -11,6 +211,11 @@ pub trait Entry: Any + Debug + Send + Sync {
         self.image_url()
     }
 
+    /// every file of this entry in order, just the content if the source does not say more
+    fn media(&self) -> Vec<Media> {
+        self.content_url().map(Media::new).into_iter().collect()
+    }
+
     fn title_url(&self) -> Option<String> {
         None
     }
@@ -27,9 +232,67 @@ pub trait Entry: Any + Debug + Send + Sync {
         None
     }
 
+    /// the tags grouped by what they describe, spelled the same as in `tags`
+    fn tag_categories(&self) -> Option<&HashMap<TagCategory, HashSet<String>>> {
+        None
+    }
+
+    fn tags_in(&self, category: TagCategory) -> Option<&HashSet<String>> {
+        self.tag_categories()?.get(&category)
+    }
+
     fn build_extra_fields(&self) -> HashMap<String, String> {
         HashMap::new()
     }
+
+    /// a copy of the (first) content on disk, if an output already downloaded (and maybe converted) it
+    fn local_file(&self) -> Option<PathBuf> {
+        None
+    }
+
+    /// mime type of the content, if something looked at it
+    fn media_type(&self) -> Option<String> {
+        None
+    }
+
+    /// all the fields of this entry as one json object, used as the context for output templates
+    fn to_json(&self) -> Value {
+        let mut tags: Vec<&String> = self.tags().map(|t| t.iter().collect()).unwrap_or_default();
+        tags.sort();
+
+        let mut characters: Vec<&String> = self.characters().map(|c| c.iter().collect()).unwrap_or_default();
+        characters.sort();
+
+        let mut categories = serde_json::Map::new();
+        for category in TagCategory::ALL {
+            if let Some(tags) = self.tags_in(category) {
+                let mut tags: Vec<&String> = tags.iter().collect();
+                tags.sort();
+                categories.insert(category.as_str().to_owned(), json!(tags));
+            }
+        }
+
+        json!({
+            "source": self.source_name(),
+            "source_tag": self.source_tag(),
+            "id": self.id(),
+            "md5": self.md5(),
+            "artist": self.artist(),
+            "characters": characters,
+            "rating": self.rating().as_str(),
+            "title": self.title(),
+            "title_url": self.title_url(),
+            "source_url": self.source_url(),
+            "content_url": self.content_url(),
+            "media": self.media(),
+            "image_url": self.image_url(),
+            "tags": tags,
+            "tag_categories": categories,
+            "extra": self.build_extra_fields(),
+            "local_file": self.local_file(),
+            "media_type": self.media_type(),
+        })
+    }
 }
 
 pub type EntryBox = Box<dyn Entry>;
@@ -60,3 +323,126 @@ impl Entry for DefaultEntry {
         (&self.tags).as_ref()
     }
 }
+
+/// an entry whose media have (partly) been saved locally, everything else comes from `inner`
+#[derive(Debug)]
+pub struct LocalEntry {
+    pub inner: EntryBox,
+    pub media: Vec<Media>,
+}
+
+impl LocalEntry {
+    /// `media` are those of `inner` with `local_file` and `media_type` filled in where known
+    pub fn wrap(inner: EntryBox, media: Vec<Media>) -> EntryBox {
+        Box::new(Self { inner, media })
+    }
+}
+
+impl Entry for LocalEntry {
+    fn source_name(&self) -> Option<String> {
+        self.inner.source_name()
+    }
+
+    fn source_tag(&self) -> Option<String> {
+        self.inner.source_tag()
+    }
+
+    fn id(&self) -> Option<String> {
+        self.inner.id()
+    }
+
+    fn md5(&self) -> Option<String> {
+        self.inner.md5()
+    }
+
+    fn artist(&self) -> Option<String> {
+        self.inner.artist()
+    }
+
+    fn characters(&self) -> Option<&HashSet<String>> {
+        self.inner.characters()
+    }
+
+    fn rating(&self) -> Rating {
+        self.inner.rating()
+    }
+
+    fn source_url(&self) -> Option<String> {
+        self.inner.source_url()
+    }
+
+    fn content_url(&self) -> Option<String> {
+        self.inner.content_url()
+    }
+
+    fn media(&self) -> Vec<Media> {
+        self.media.clone()
+    }
+
+    fn title_url(&self) -> Option<String> {
+        self.inner.title_url()
+    }
+
+    fn image_url(&self) -> Option<String> {
+        self.inner.image_url()
+    }
+
+    fn title(&self) -> Option<String> {
+        self.inner.title()
+    }
+
+    fn tags(&self) -> Option<&HashSet<String>> {
+        self.inner.tags()
+    }
+
+    fn tag_categories(&self) -> Option<&HashMap<TagCategory, HashSet<String>>> {
+        self.inner.tag_categories()
+    }
+
+    fn build_extra_fields(&self) -> HashMap<String, String> {
+        self.inner.build_extra_fields()
+    }
+
+    fn local_file(&self) -> Option<PathBuf> {
+        self.media.first()?.local_file.clone()
+    }
+
+    fn media_type(&self) -> Option<String> {
+        self.media
+            .first()
+            .and_then(|m| m.media_type.clone())
+            .or_else(|| self.inner.media_type())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ratings_parse_both_spellings() {
+        for (s, rating) in [
+            ("s", Rating::Safe),
+            ("safe", Rating::Safe),
+            ("general", Rating::Safe),
+            ("G", Rating::Safe),
+            ("q", Rating::Questionable),
+            ("Questionable", Rating::Questionable),
+            ("sensitive", Rating::Questionable),
+            (" e ", Rating::Explicit),
+            ("EXPLICIT", Rating::Explicit),
+            ("", Rating::Unknown),
+            ("x", Rating::Unknown),
+            ("safe-ish", Rating::Unknown),
+        ] {
+            assert_eq!(Rating::parse(s), rating, "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn ratings_round_trip() {
+        for rating in [Rating::Safe, Rating::Questionable, Rating::Explicit, Rating::Unknown] {
+            assert_eq!(Rating::parse(rating.as_str()), rating);
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use std::fmt;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// what can go wrong while talking to a site or keeping track of state
+#[derive(Debug)]
+pub enum Error {
+    /// the request did not go through or came back with an error status
+    Network(reqwest::Error),
+
+    /// something could not be read, e.g. an id that is not a number
+    Parse(String),
+
+    /// the database or a file could not be read or written
+    Storage(String),
+
+    /// the site answered, but not in a shape we know, it probably changed its markup
+    SiteChanged(String),
+}
+
+impl Error {
+    pub fn parse(what: impl fmt::Display) -> Self {
+        Error::Parse(what.to_string())
+    }
+
+    pub fn site_changed(what: impl fmt::Display) -> Self {
+        Error::SiteChanged(what.to_string())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Network(e) => write!(f, "network error: {}", e),
+            Error::Parse(what) => write!(f, "could not parse {}", what),
+            Error::Storage(what) => write!(f, "storage error: {}", what),
+            Error::SiteChanged(what) => write!(f, "site changed: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Network(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Network(e)
+    }
+}
+
+impl From<std::num::ParseIntError> for Error {
+    fn from(e: std::num::ParseIntError) -> Self {
+        Error::Parse(format!("number: {}", e))
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::Storage(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Parse(format!("stored value: {}", e))
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Storage(e.to_string())
+    }
+}
@@ -8,10 +8,12 @@ pub trait AggregatorFactory {
 
     const NAME: &'static str;
 
+    /// every aggregator along with a name that stays the same across restarts and config edits,
+    /// e.g. `gelbooru[some_tag]`, what is remembered about it is kept under that name
     fn build_aggregators(
         config: Self::Config,
         state: &State,
-    ) -> Vec<Box<dyn Aggregator<Item = EntryBox, PipelineState = State>>>;
+    ) -> Vec<(String, Box<dyn Aggregator<Item = EntryBox, PipelineState = State>>)>;
 }
 
 pub trait OutputFilterFactory {
@@ -19,5 +21,8 @@ pub trait OutputFilterFactory {
 
     const NAME: &'static str;
 
-    fn build_filters(config: Self::Config) -> Vec<Box<dyn OutputFilter<Item = EntryBox>>>;
+    fn build_filters(
+        config: Self::Config,
+        state: &State,
+    ) -> Vec<Box<dyn OutputFilter<Item = EntryBox>>>;
 }
@@ -0,0 +1,643 @@
+use crate::{error, log, schedule, state::ValidatorLog, Error};
+use async_bucket::AsyncBucket;
+use reqwest::{cookie::Jar, header, Client, IntoUrl, Proxy, Request, RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+fn _produce_1_0() -> f64 {
+    1.0
+}
+
+fn _produce_1() -> usize {
+    1
+}
+
+fn _produce_user_agent() -> String {
+    format!("anke/{}", env!("CARGO_PKG_VERSION"))
+}
+
+fn _produce_10_0() -> f64 {
+    10.0
+}
+
+fn _produce_90_0() -> f64 {
+    90.0
+}
+
+/// how the shared client talks to the outside world, all durations in seconds
+#[derive(Debug, Deserialize, Clone)]
+pub struct HttpConfig {
+    #[serde(default = "_produce_user_agent")]
+    pub user_agent: String,
+
+    // e.g. "http://localhost:8080" or "socks5://localhost:1080", sources can set their own
+    pub proxy: Option<String>,
+
+    #[serde(default = "_produce_10_0")]
+    pub connect_timeout: f64,
+
+    // for the whole request including reading the body, so mind big downloads,
+    // none by default
+    pub timeout: Option<f64>,
+
+    // a cookies.txt as exported by browsers, e.g. to be logged in on a site
+    pub cookies: Option<PathBuf>,
+
+    // idle connections are kept around this long to be reused
+    #[serde(default = "_produce_90_0")]
+    pub pool_idle_timeout: f64,
+
+    // for development, pages are kept in here and not fetched again
+    pub cache: Option<PathBuf>,
+
+    // in seconds, how long something in `cache` is good for, forever if unset
+    pub cache_ttl: Option<f64>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            user_agent: _produce_user_agent(),
+            proxy: None,
+            connect_timeout: _produce_10_0(),
+            timeout: None,
+            cookies: None,
+            pool_idle_timeout: _produce_90_0(),
+            cache: None,
+            cache_ttl: None,
+        }
+    }
+}
+
+/// how many requests a host gets, shared by everything that talks to it
+#[derive(Debug, Deserialize, Clone)]
+pub struct RateLimit {
+    // in seconds, one more request is allowed after this long
+    #[serde(default = "_produce_1_0")]
+    pub every: f64,
+
+    // how many requests can go out at once after a quiet period
+    #[serde(default = "_produce_1")]
+    pub burst: usize,
+}
+
+// in seconds, anything slower than a request a day is a typo rather than a rate limit
+const MAX_EVERY: f64 = 24.0 * 60.0 * 60.0;
+
+impl RateLimit {
+    pub fn validate(&self) -> error::Result<()> {
+        if self.every.is_finite() && (0.0..=MAX_EVERY).contains(&self.every) {
+            Ok(())
+        } else {
+            Err(Error::parse(format!("rate limit of a request every {} seconds", self.every)))
+        }
+    }
+
+    fn bucket(&self) -> AsyncBucket {
+        let burst = self.burst.max(1);
+        AsyncBucket::new(Duration::from_secs_f64(self.every.max(0.001)), burst).init(burst)
+    }
+}
+
+// in seconds, whatever a server says we never wait longer than this at once
+pub const MAX_RETRY_AFTER: f64 = 600.0;
+
+// in seconds, for when neither the server nor the config said anything sane
+const DEFAULT_RETRY_AFTER: f64 = 5.0;
+
+/// how long to wait before retrying, `retry_after` is what the server said and `fallback` is used
+/// when that is missing or not a sane number of seconds, both are capped at [`MAX_RETRY_AFTER`]
+pub fn retry_after(retry_after: Option<f64>, fallback: f64) -> Duration {
+    let sane = |secs: f64| secs.is_finite().then(|| secs.clamp(0.0, MAX_RETRY_AFTER));
+
+    let secs = retry_after
+        .and_then(sane)
+        .or_else(|| sane(fallback))
+        .unwrap_or(DEFAULT_RETRY_AFTER);
+
+    Duration::from_secs_f64(secs)
+}
+
+/// reads a netscape cookies.txt into `jar`, returns how many cookies were loaded from it;
+/// expired cookies and lines that are not cookies are skipped
+fn load_cookies(path: &Path, jar: &Jar) -> error::Result<usize> {
+    let raw = std::fs::read_to_string(path)?;
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let mut cnt = 0;
+
+    for line in raw.lines() {
+        // curl marks http-only cookies like this, everything else starting with # is a comment
+        let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        let (domain, subdomains, path, secure, expires, name, value) = match fields[..] {
+            [domain, subdomains, path, secure, expires, name, value] => {
+                (domain, subdomains, path, secure, expires, name, value)
+            }
+            _ => {
+                log::warn!("Skipping cookie line {:?} in {:?}", line, path);
+                continue;
+            }
+        };
+
+        // 0 is a session cookie, which is kept for as long as we run
+        let expires = match expires.trim().parse::<u64>() {
+            Ok(expires) => expires,
+            Err(_) => {
+                log::warn!("Skipping cookie {} for {} with expiry {:?}", name, domain, expires);
+                continue;
+            }
+        };
+        if expires != 0 && expires <= now {
+            log::debug!("Skipping expired cookie {} for {}", name, domain);
+            continue;
+        }
+
+        let host = domain.trim_start_matches('.');
+        let scheme = if secure.eq_ignore_ascii_case("TRUE") { "https" } else { "http" };
+        let url = match reqwest::Url::parse(&format!("{}://{}{}", scheme, host, path)) {
+            Ok(url) => url,
+            Err(e) => {
+                log::warn!("Skipping cookie {} for {:?}: {}", name, domain, e);
+                continue;
+            }
+        };
+
+        let mut cookie = format!("{}={}; Path={}", name, value, path);
+        // without a domain the cookie only goes back to the very host it is for
+        if subdomains.eq_ignore_ascii_case("TRUE") {
+            cookie.push_str(&format!("; Domain={}", host));
+        }
+        if scheme == "https" {
+            cookie.push_str("; Secure");
+        }
+        if expires != 0 {
+            cookie.push_str(&format!("; Max-Age={}", expires - now));
+        }
+
+        jar.add_cookie_str(&cookie, &url);
+        cnt += 1;
+    }
+
+    Ok(cnt)
+}
+
+/// what a server said about the version of a page it sent, so it can tell us next time whether it changed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Validators {
+    pub url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    fn of(url: &str, res: &Response) -> Self {
+        let header = |name| {
+            res.headers()
+                .get(name)
+                .and_then(|h| h.to_str().ok())
+                .map(str::to_owned)
+        };
+
+        Self {
+            url: url.to_owned(),
+            etag: header(header::ETAG),
+            last_modified: header(header::LAST_MODIFIED),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+/// the client every source and sink should make their requests through,
+/// requests to hosts with a [`RateLimit`] wait for their turn first
+#[derive(Clone)]
+pub struct Http {
+    client: Client,
+    config: HttpConfig,
+    jar: Arc<Jar>,
+    buckets: Arc<Mutex<HashMap<String, Arc<AsyncBucket>>>>,
+    validators: Option<ValidatorLog>,
+}
+
+impl fmt::Debug for Http {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hosts: Vec<String> = self
+            .buckets
+            .lock()
+            .map(|b| b.keys().cloned().collect())
+            .unwrap_or_default();
+
+        f.debug_struct("Http").field("limited", &hosts).finish()
+    }
+}
+
+impl Http {
+    pub fn new(config: HttpConfig) -> error::Result<Self> {
+        let jar = Arc::new(Jar::default());
+        if let Some(path) = &config.cookies {
+            let cnt = load_cookies(path, &jar)?;
+            log::info!("Loaded {} cookies from {:?}", cnt, path);
+        }
+
+        Ok(Self {
+            client: Self::build_client(&config, &jar, config.proxy.as_deref())?,
+            config,
+            jar,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+            validators: None,
+        })
+    }
+
+    /// where validators get remembered, done by `State` when it is handed this client
+    pub(crate) fn with_validators(self, validators: ValidatorLog) -> Self {
+        Self {
+            validators: Some(validators),
+            ..self
+        }
+    }
+
+    fn build_client(config: &HttpConfig, jar: &Arc<Jar>, proxy: Option<&str>) -> error::Result<Client> {
+        let secs = |what, value| schedule::seconds(what, value, "[main.http]").map(Duration::from_secs_f64);
+
+        let mut builder = Client::builder()
+            .user_agent(&config.user_agent)
+            .connect_timeout(secs("connect_timeout", config.connect_timeout)?)
+            .pool_idle_timeout(secs("pool_idle_timeout", config.pool_idle_timeout)?)
+            .cookie_provider(Arc::clone(jar));
+
+        if let Some(timeout) = config.timeout {
+            builder = builder.timeout(secs("timeout", timeout)?);
+        }
+
+        if let Some(proxy) = proxy {
+            builder = builder.proxy(Proxy::all(proxy)?);
+        }
+
+        Ok(builder.build()?)
+    }
+
+    /// the same client going through `proxy`, it still shares cookies and rate limits with this one
+    pub fn with_proxy(&self, proxy: &str) -> error::Result<Self> {
+        Ok(Self {
+            client: Self::build_client(&self.config, &self.jar, Some(proxy))?,
+            ..self.clone()
+        })
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// limits requests to `host` and its subdomains, the first limit given for a host wins
+    pub fn limit(&self, host: impl Into<String>, limit: &RateLimit) -> error::Result<()> {
+        limit.validate()?;
+
+        let host = host.into().to_lowercase();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.contains_key(&host) {
+            log::debug!("{} is already limited, ignoring {:?}", host, limit);
+            return Ok(());
+        }
+
+        log::debug!("Limiting {} to {:?}", host, limit);
+        buckets.insert(host, Arc::new(limit.bucket()));
+        Ok(())
+    }
+
+    /// the bucket of `host` or the closest parent domain that has one
+    fn bucket_for(&self, host: &str) -> Option<Arc<AsyncBucket>> {
+        let buckets = self.buckets.lock().unwrap();
+        let host = host.to_lowercase();
+
+        let mut domain = host.as_str();
+        loop {
+            if let Some(bucket) = buckets.get(domain) {
+                return Some(Arc::clone(bucket));
+            }
+
+            domain = domain.split_once('.')?.1;
+        }
+    }
+
+    pub fn get(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.client.get(url)
+    }
+
+    pub fn post(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.client.post(url)
+    }
+
+    pub fn request(&self, method: reqwest::Method, url: impl IntoUrl) -> RequestBuilder {
+        self.client.request(method, url)
+    }
+
+    pub async fn execute(&self, req: Request) -> reqwest::Result<Response> {
+        if let Some(bucket) = req.url().host_str().and_then(|h| self.bucket_for(h)) {
+            bucket.take(1).await;
+        }
+
+        self.client.execute(req).await
+    }
+
+    /// sends `req`, once the host it goes to has room for it
+    pub async fn send(&self, req: RequestBuilder) -> reqwest::Result<Response> {
+        self.execute(req.build()?).await
+    }
+
+    /// the body of `url` as text, failing on error statuses
+    pub async fn text(&self, url: impl IntoUrl) -> error::Result<String> {
+        let url = url.into_url()?;
+        if let Some(body) = self.cached(url.as_str()).await {
+            return Ok(body);
+        }
+
+        let body = self
+            .send(self.get(url.clone()))
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        self.cache(url.as_str(), &body).await;
+        Ok(body)
+    }
+
+    /// like [`Http::text`], but `None` if `url` did not change since its validators were last
+    /// [remembered](Http::remember), the ones that came with the new body are handed back
+    pub async fn text_if_changed(&self, url: impl IntoUrl) -> error::Result<Option<(String, Validators)>> {
+        let url = url.into_url()?;
+        if let Some(body) = self.cached(url.as_str()).await {
+            return Ok(Some((body, Validators::default())));
+        }
+
+        let mut req = self.get(url.clone());
+        let known = match &self.validators {
+            Some(log) => log.get(url.as_str()).await?,
+            None => None,
+        };
+
+        if let Some(known) = known {
+            if let Some(etag) = &known.etag {
+                req = req.header(header::IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &known.last_modified {
+                req = req.header(header::IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        let res = self.send(req).await?;
+        if res.status() == StatusCode::NOT_MODIFIED {
+            log::debug!("{} did not change", url);
+            return Ok(None);
+        }
+
+        let res = res.error_for_status()?;
+        let validators = Validators::of(url.as_str(), &res);
+        let body = res.text().await?;
+
+        self.cache(url.as_str(), &body).await;
+        Ok(Some((body, validators)))
+    }
+
+    /// makes the next [`Http::text_if_changed`] of the same url only get it again if it changed since,
+    /// best done once whatever was in it has been dealt with
+    pub async fn remember(&self, validators: &Validators) -> error::Result<()> {
+        match &self.validators {
+            Some(log) if !validators.is_empty() => log.set(validators).await,
+            _ => Ok(()),
+        }
+    }
+
+    fn cache_path(&self, url: &str) -> Option<PathBuf> {
+        let dir = self.config.cache.as_ref()?;
+
+        let mut hasher = DefaultHasher::new();
+        url.hash(&mut hasher);
+
+        Some(dir.join(format!("{:016x}", hasher.finish())))
+    }
+
+    /// what the development cache has for `url`, if it is enabled and has something fresh enough
+    async fn cached(&self, url: &str) -> Option<String> {
+        let path = self.cache_path(url)?;
+        let meta = tokio::fs::metadata(&path).await.ok()?;
+
+        if let Some(ttl) = self.config.cache_ttl {
+            let age = meta
+                .modified()
+                .ok()
+                .and_then(|m| SystemTime::now().duration_since(m).ok())
+                .unwrap_or_default();
+
+            if age.as_secs_f64() > ttl {
+                return None;
+            }
+        }
+
+        log::debug!("Using cached {} from {:?}", url, path);
+        tokio::fs::read_to_string(&path).await.ok()
+    }
+
+    async fn cache(&self, url: &str, body: &str) {
+        let path = match self.cache_path(url) {
+            Some(path) => path,
+            None => return,
+        };
+
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await.ok();
+        }
+
+        if let Err(e) = tokio::fs::write(&path, body).await {
+            log::warn!("Could not cache {} at {:?}: {}", url, path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::{mock, Matcher};
+    use reqwest::cookie::CookieStore;
+    use std::io::Write;
+
+    #[test]
+    fn limits_apply_to_subdomains() {
+        let http = Http::new(HttpConfig::default()).unwrap();
+        let limit = RateLimit { every: 1.0, burst: 1 };
+        http.limit("Example.com", &limit).unwrap();
+        http.limit("api.other.org", &limit).unwrap();
+
+        let bucket = http.bucket_for("example.com").unwrap();
+        assert!(Arc::ptr_eq(&bucket, &http.bucket_for("img.cdn.EXAMPLE.com").unwrap()));
+        assert!(http.bucket_for("v1.api.other.org").is_some());
+        for unlimited in ["notexample.com", "com", "other.org", "127.0.0.1"] {
+            assert!(http.bucket_for(unlimited).is_none(), "{}", unlimited);
+        }
+
+        // the first one given wins, and proxied clients still share it
+        http.limit("example.com", &RateLimit { every: 5.0, burst: 3 }).unwrap();
+        assert!(Arc::ptr_eq(&bucket, &http.bucket_for("example.com").unwrap()));
+        let proxied = http.with_proxy("http://127.0.0.1:1").unwrap();
+        assert!(Arc::ptr_eq(&bucket, &proxied.bucket_for("example.com").unwrap()));
+    }
+
+    #[test]
+    fn broken_timeouts_are_refused() {
+        let http = |config: &str| Http::new(toml::from_str(config).unwrap());
+
+        assert!(http("connect_timeout = 0.0\ntimeout = 120.0\npool_idle_timeout = 90.0").is_ok());
+        for bad in ["connect_timeout = inf", "pool_idle_timeout = nan", "timeout = -1.0", "timeout = 1e300"] {
+            assert!(http(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn broken_limits_are_refused() {
+        let http = Http::new(HttpConfig::default()).unwrap();
+        for every in [f64::INFINITY, f64::NAN, -1.0, 1e300] {
+            assert!(http.limit("example.com", &RateLimit { every, burst: 1 }).is_err(), "{}", every);
+        }
+        assert!(http.bucket_for("example.com").is_none());
+
+        assert!(http.limit("example.com", &RateLimit { every: 0.0, burst: 0 }).is_ok());
+    }
+
+    #[test]
+    fn retry_after_is_clamped() {
+        assert_eq!(retry_after(Some(1.5), 5.0), Duration::from_secs_f64(1.5));
+        assert_eq!(retry_after(None, 5.0), Duration::from_secs(5));
+        assert_eq!(retry_after(Some(f64::INFINITY), 5.0), Duration::from_secs(5));
+        assert_eq!(retry_after(Some(f64::NAN), 60.0), Duration::from_secs(60));
+        assert_eq!(retry_after(Some(-1.0), 5.0), Duration::ZERO);
+        assert_eq!(retry_after(None, -3.0), Duration::ZERO);
+        assert_eq!(retry_after(Some(1e300), 5.0), Duration::from_secs_f64(MAX_RETRY_AFTER));
+        assert_eq!(retry_after(None, f64::INFINITY), Duration::from_secs(5));
+        assert_eq!(retry_after(Some(f64::NAN), f64::NAN), Duration::from_secs(5));
+    }
+
+    // sorted, the jar hands them out in no particular order
+    fn cookies(jar: &Jar, url: &str) -> String {
+        let header = jar.cookies(&url.parse().unwrap());
+        let mut cookies: Vec<_> = header
+            .as_ref()
+            .map(|v| v.to_str().unwrap().split("; ").collect())
+            .unwrap_or_default();
+        cookies.sort_unstable();
+        cookies.join("; ")
+    }
+
+    #[test]
+    fn loads_cookies_txt() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(
+            b"# Netscape HTTP Cookie File\n\
+              # https://curl.se/docs/http-cookies.html\n\
+              \n\
+              .example.com\tTRUE\t/\tFALSE\t0\tsession\tabc\n\
+              #HttpOnly_.example.com\tTRUE\t/\tTRUE\t4102444800\tlogin\tdef\n\
+              #HttpOnly_only.example.org\tFALSE\t/\tFALSE\t4102444800\thost\tghi\n\
+              .example.com\tTRUE\t/\tFALSE\t946684800\told\tgone\n\
+              .example.com\tTRUE\t/\tFALSE\tsoon\tbroken\texpiry\n\
+              this is not a cookie\n\
+              .example.com\tTRUE\t/private\tFALSE\t0\tdeep\tjkl\n",
+        )
+        .unwrap();
+
+        let jar = Jar::default();
+        assert_eq!(load_cookies(file.path(), &jar).unwrap(), 4);
+
+        assert_eq!(cookies(&jar, "http://www.example.com/"), "session=abc");
+        assert_eq!(cookies(&jar, "https://example.com/"), "login=def; session=abc");
+        assert_eq!(cookies(&jar, "http://example.com/private/x"), "deep=jkl; session=abc");
+
+        // not for subdomains, so only the host itself
+        assert_eq!(cookies(&jar, "http://only.example.org/"), "host=ghi");
+        assert_eq!(cookies(&jar, "http://sub.only.example.org/"), "");
+    }
+
+    #[tokio::test]
+    async fn unchanged_pages_are_not_fetched_again() {
+        let state = crate::State::new(":memory:".into()).unwrap();
+        let http = state.http();
+        let url = format!("{}/validated-page", mockito::server_url());
+
+        let fresh = mock("GET", "/validated-page")
+            .match_header("if-none-match", Matcher::Missing)
+            .match_header("if-modified-since", Matcher::Missing)
+            .with_header("etag", "\"v1\"")
+            .with_header("last-modified", "Wed, 21 Oct 2015 07:28:00 GMT")
+            .with_body("first")
+            .expect(2)
+            .create();
+
+        let (body, validators) = http.text_if_changed(&url).await.unwrap().unwrap();
+        assert_eq!(body, "first");
+        assert_eq!(
+            validators,
+            Validators {
+                url: url.clone(),
+                etag: Some("\"v1\"".into()),
+                last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".into()),
+            }
+        );
+
+        // nothing is sent along until they are remembered
+        assert!(http.text_if_changed(&url).await.unwrap().is_some());
+        fresh.assert();
+
+        http.remember(&validators).await.unwrap();
+        let unchanged = mock("GET", "/validated-page")
+            .match_header("if-none-match", "\"v1\"")
+            .match_header("if-modified-since", "Wed, 21 Oct 2015 07:28:00 GMT")
+            .with_status(304)
+            .create();
+
+        assert_eq!(http.text_if_changed(&url).await.unwrap(), None);
+        unchanged.assert();
+    }
+
+    #[tokio::test]
+    async fn the_dev_cache_is_used_before_asking() {
+        let dir = tempfile::tempdir().unwrap();
+        let http = Http::new(HttpConfig {
+            cache: Some(dir.path().to_owned()),
+            ..Default::default()
+        })
+        .unwrap();
+        let url = format!("{}/cached-page", mockito::server_url());
+
+        let page = mock("GET", "/cached-page")
+            .with_header("etag", "\"v1\"")
+            .with_body("page")
+            .expect(1)
+            .create();
+
+        let (body, validators) = http.text_if_changed(&url).await.unwrap().unwrap();
+        assert_eq!((body.as_str(), validators.etag.as_deref()), ("page", Some("\"v1\"")));
+
+        // the cached copy comes without validators, so nothing gets remembered for it
+        let (body, validators) = http.text_if_changed(&url).await.unwrap().unwrap();
+        assert_eq!(body, "page");
+        assert!(validators.is_empty());
+        assert_eq!(http.text(&url).await.unwrap(), "page");
+
+        page.assert();
+    }
+}
@@ -28,8 +28,11 @@ pub fn setup() -> Result<()> {
     Ok(())
 }
 
+pub mod error;
+pub use error::Error;
+
 pub mod entry;
-pub use entry::{Entry, EntryBox};
+pub use entry::{Entry, EntryBox, LocalEntry, Media, MediaKind, Rating, TagCategory};
 
 pub mod url;
 
@@ -37,14 +40,28 @@ pub use async_bucket;
 
 use async_aggregation_pipeline::prelude;
 
+mod migrations;
+
 mod state;
-pub use state::{State, TokenStorageConnection};
+pub use state::{FileLog, FileRecord, State, TokenStorageConnection, Usage};
+
+pub mod http;
+pub use http::{Http, HttpConfig, RateLimit, Validators};
+
+pub mod storage;
+pub use storage::{LocalStorage, MemoryStorage, Storage};
 
 pub type Pipeline = prelude::Pipeline<EntryBox, State>;
 
 mod factory;
 pub use factory::{AggregatorFactory, OutputFilterFactory};
 
+mod supervisor;
+pub use supervisor::{BackoffPolicy, Health, HealthStatus, Supervised};
+
+pub mod schedule;
+pub use schedule::{ScheduleConfig, Scheduled};
+
 pub use toml;
 
 pub use prelude::{Aggregator, Context, OutputFilter, PipelineResult};
@@ -0,0 +1,118 @@
+use crate::{error, log, Error};
+use rusqlite::{params, Connection, TransactionBehavior};
+
+/// every change to the schema, in order, the index + 1 is the version it brings the database to,
+/// never edit or reorder these once released, only add new ones to the end
+const MIGRATIONS: &[&str] = &[
+    // 1, databases from before versioning already have this
+    "CREATE TABLE IF NOT EXISTS tokens ( token_group TEXT NOT NULL, token_id TEXT NOT NULL, token TEXT, UNIQUE(token_group, token_id) ON CONFLICT REPLACE );",
+    // 2, and might have this
+    "CREATE TABLE IF NOT EXISTS files ( sink TEXT NOT NULL, source TEXT NOT NULL, file_key TEXT NOT NULL, size INTEGER NOT NULL, written_at INTEGER NOT NULL, UNIQUE(sink, file_key) ON CONFLICT REPLACE );",
+    // 3
+    "CREATE TABLE IF NOT EXISTS http_validators ( url TEXT NOT NULL PRIMARY KEY ON CONFLICT REPLACE, etag TEXT, last_modified TEXT );",
+    // 4
+    "CREATE TABLE kv ( kv_group TEXT NOT NULL, kv_id TEXT NOT NULL, key TEXT NOT NULL, value TEXT NOT NULL, expires_at INTEGER, PRIMARY KEY (kv_group, kv_id, key) ON CONFLICT REPLACE );",
+];
+
+fn version(conn: &Connection) -> error::Result<usize> {
+    let version: i64 = conn.query_row("SELECT COALESCE(MAX(version), 0) FROM schema_version", [], |row| row.get(0))?;
+
+    Ok(version as usize)
+}
+
+/// brings the database up to the newest schema, refuses to touch one that is newer than this build
+pub(crate) fn migrate(conn: &mut Connection) -> error::Result<()> {
+    // taking the write lock right away, so two processes starting on the same file
+    // cannot both read the same version and then both apply what comes after it;
+    // either all of it happens or none of it
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version ( version INTEGER NOT NULL PRIMARY KEY, applied_at INTEGER NOT NULL );",
+        [],
+    )?;
+
+    let current = version(&tx)?;
+    if current > MIGRATIONS.len() {
+        return Err(Error::Storage(format!(
+            "the database is at schema version {} but this build only knows up to {}, refusing to downgrade it",
+            current,
+            MIGRATIONS.len()
+        )));
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(current) {
+        let version = i + 1;
+        log::info!("Migrating the database to schema version {}", version);
+
+        tx.execute_batch(migration)?;
+        tx.execute(
+            "INSERT INTO schema_version (version, applied_at) VALUES (?1, ?2)",
+            params![version as i64, crate::state::now() as i64],
+        )?;
+    }
+
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count(conn: &Connection, table: &str) -> i64 {
+        conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn fresh_databases_get_everything() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+
+        assert_eq!(version(&conn).unwrap(), MIGRATIONS.len());
+        for table in ["tokens", "files", "http_validators", "kv"] {
+            assert_eq!(count(&conn, table), 0);
+        }
+
+        // nothing left to do the second time
+        migrate(&mut conn).unwrap();
+        assert_eq!(count(&conn, "schema_version"), MIGRATIONS.len() as i64);
+    }
+
+    #[test]
+    fn databases_from_before_versioning_keep_their_data() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.execute_batch(MIGRATIONS[1]).unwrap();
+        conn.execute_batch(
+            "INSERT INTO tokens VALUES ('gelbooru', 'cat_ears', '1234');
+             INSERT INTO files VALUES ('files', 'gelbooru', 'a.png', 10, 0);",
+        )
+        .unwrap();
+
+        migrate(&mut conn).unwrap();
+
+        assert_eq!(version(&conn).unwrap(), MIGRATIONS.len());
+        let token: String = conn
+            .query_row("SELECT token FROM tokens WHERE token_id = 'cat_ears'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(token, "1234");
+        assert_eq!(count(&conn, "files"), 1);
+        assert_eq!(count(&conn, "kv"), 0);
+    }
+
+    #[test]
+    fn newer_databases_are_left_alone() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+
+        let newer = MIGRATIONS.len() as i64 + 1;
+        conn.execute("INSERT INTO schema_version VALUES (?1, 0)", params![newer]).unwrap();
+
+        assert!(migrate(&mut conn).is_err());
+        assert_eq!(version(&conn).unwrap(), newer as usize);
+        // and not stuck in the transaction either
+        assert!(conn.is_autocommit());
+    }
+}
@@ -0,0 +1,256 @@
+use crate::{error, log, Aggregator, Context, EntryBox, Error, PipelineResult, State};
+use async_trait::async_trait;
+use rand::Rng;
+use serde::Deserialize;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::time::{self, Instant};
+
+// cron schedules fire on the minute, spreading happens inside of that
+const CRON_SPREAD: f64 = 60.0;
+
+// anything longer is a typo, and sleeping until then would overflow the clock
+const MAX_SECS: f64 = 366.0 * 24.0 * 3600.0;
+
+/// when an aggregator gets polled, every field falls back to the section it is in
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct ScheduleConfig {
+    // in seconds between two polls
+    pub interval: Option<f64>,
+
+    // each poll happens up to this many seconds later than planned
+    pub jitter: Option<f64>,
+
+    // cron-like, e.g. "*/15 * * * *", takes precedence over `interval`
+    pub schedule: Option<String>,
+}
+
+impl ScheduleConfig {
+    /// fills whatever is unset in `self` from `other`
+    pub fn or(self, other: &ScheduleConfig) -> Self {
+        Self {
+            interval: self.interval.or(other.interval),
+            jitter: self.jitter.or(other.jitter),
+            schedule: self.schedule.or_else(|| other.schedule.clone()),
+        }
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.interval.is_some() || self.schedule.is_some()
+    }
+}
+
+enum Plan {
+    Every(Duration),
+    Cron(Box<cron::Schedule>),
+}
+
+/// polls the wrapped aggregator according to a [`ScheduleConfig`] instead of whenever the pipeline asks
+pub struct Scheduled {
+    name: String,
+    inner: Box<dyn Aggregator<Item = EntryBox, PipelineState = State>>,
+    plan: Plan,
+    jitter: f64,
+    // where in the interval or minute this one sits, so siblings do not all fire at once
+    offset: Duration,
+    next: Option<Instant>,
+}
+
+impl Scheduled {
+    /// wraps `inner` as the `slot`th of `slots` aggregators sharing `config`,
+    /// hands it back untouched if nothing is configured
+    pub fn wrap(
+        name: String,
+        inner: Box<dyn Aggregator<Item = EntryBox, PipelineState = State>>,
+        config: &ScheduleConfig,
+        slot: usize,
+        slots: usize,
+    ) -> error::Result<Box<dyn Aggregator<Item = EntryBox, PipelineState = State>>> {
+        let plan = match (&config.schedule, config.interval) {
+            (Some(expr), _) => Plan::Cron(Box::new(parse_cron(expr)?)),
+            (None, Some(secs)) if secs > 0.0 => {
+                Plan::Every(Duration::from_secs_f64(seconds("interval", secs, &name)?))
+            }
+            (None, Some(secs)) => return Err(Error::parse(format!("interval {} of {}", secs, name))),
+            (None, None) => return Ok(inner),
+        };
+        let jitter = seconds("jitter", config.jitter.unwrap_or(0.0), &name)?;
+
+        let spread = match &plan {
+            Plan::Every(every) => every.as_secs_f64(),
+            Plan::Cron(_) => CRON_SPREAD,
+        };
+
+        Ok(Box::new(Self {
+            name,
+            inner,
+            plan,
+            jitter,
+            offset: offset(spread, slot, slots),
+            next: None,
+        }))
+    }
+
+    fn jitter(&self) -> Duration {
+        if self.jitter > 0.0 {
+            Duration::from_secs_f64(rand::thread_rng().gen_range(0.0..self.jitter))
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    /// when the poll after the one at `last` should happen, `None` for the very first one
+    fn after(&self, last: Option<Instant>) -> Instant {
+        let now = Instant::now();
+
+        let at = match (&self.plan, last) {
+            (Plan::Every(_), None) => now + self.offset,
+            (Plan::Every(every), Some(last)) => (last + *every).max(now),
+            (Plan::Cron(schedule), _) => {
+                let until = schedule
+                    .upcoming(chrono::Utc)
+                    .next()
+                    .and_then(|at| (at - chrono::Utc::now()).to_std().ok())
+                    .unwrap_or_default();
+                now + until + self.offset
+            }
+        };
+
+        at + self.jitter()
+    }
+}
+
+/// checks that `value` is a number of seconds that can be slept for
+pub(crate) fn seconds(what: &str, value: f64, name: &str) -> error::Result<f64> {
+    if value.is_finite() && (0.0..=MAX_SECS).contains(&value) {
+        Ok(value)
+    } else {
+        Err(Error::parse(format!(
+            "{} {} of {}, has to be between 0 and {}",
+            what, value, name, MAX_SECS
+        )))
+    }
+}
+
+/// where the `slot`th of `slots` siblings sits within `spread` seconds, evenly apart
+fn offset(spread: f64, slot: usize, slots: usize) -> Duration {
+    Duration::from_secs_f64(spread * slot as f64 / slots.max(1) as f64)
+}
+
+/// parses `expr` as a cron expression, the usual five fields or the crate's own six or seven with seconds
+fn parse_cron(expr: &str) -> error::Result<cron::Schedule> {
+    let expr = match expr.split_whitespace().count() {
+        5 => format!("0 {}", expr),
+        _ => expr.to_owned(),
+    };
+
+    cron::Schedule::from_str(&expr).map_err(|e| Error::parse(format!("schedule {:?}: {}", expr, e)))
+}
+
+#[async_trait]
+impl Aggregator for Scheduled {
+    type Item = EntryBox;
+    type PipelineState = State;
+
+    fn on_load(&mut self) {
+        self.inner.on_load();
+    }
+
+    async fn poll(
+        &mut self,
+        ctx: &mut Context<Self::Item, Self::PipelineState>,
+    ) -> PipelineResult<()> {
+        let at = match self.next {
+            Some(at) => at,
+            None => self.after(None),
+        };
+
+        log::trace!("{} is next polled in {:?}", self.name, at.saturating_duration_since(Instant::now()));
+        time::sleep_until(at).await;
+
+        let started = Instant::now();
+        let res = self.inner.poll(ctx).await;
+
+        // interval polls are counted from when they started, not when they finished
+        self.next = Some(self.after(Some(started)));
+
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Nothing;
+
+    #[async_trait]
+    impl Aggregator for Nothing {
+        type Item = EntryBox;
+        type PipelineState = State;
+
+        async fn poll(
+            &mut self,
+            _: &mut Context<Self::Item, Self::PipelineState>,
+        ) -> PipelineResult<()> {
+            Ok(())
+        }
+    }
+
+    fn wrap(interval: Option<f64>, jitter: Option<f64>, schedule: Option<&str>) -> error::Result<()> {
+        let config = ScheduleConfig {
+            interval,
+            jitter,
+            schedule: schedule.map(str::to_owned),
+        };
+        Scheduled::wrap("test".into(), Box::new(Nothing), &config, 0, 1).map(|_| ())
+    }
+
+    #[test]
+    fn cron_takes_five_fields_or_six_and_seven() {
+        let five = parse_cron("*/15 * * * *").unwrap();
+        let six = parse_cron("0 */15 * * * *").unwrap();
+        assert_eq!(five.to_string(), six.to_string());
+        assert_eq!(five.to_string(), "0 */15 * * * *");
+
+        assert!(parse_cron("0 0 12 * * Mon 2030").is_ok());
+    }
+
+    #[test]
+    fn invalid_cron_is_refused() {
+        assert!(parse_cron("").is_err());
+        assert!(parse_cron("every five minutes").is_err());
+        assert!(parse_cron("61 * * * *").is_err());
+        assert!(parse_cron("* * *").is_err());
+        assert!(wrap(None, None, Some("* * * * * * * *")).is_err());
+    }
+
+    #[test]
+    fn refuses_what_cannot_be_slept_for() {
+        assert!(wrap(Some(600.0), Some(30.0), None).is_ok());
+        assert!(wrap(None, Some(30.0), Some("* * * * *")).is_ok());
+        assert!(wrap(None, Some(f64::INFINITY), None).is_ok(), "nothing to schedule");
+
+        for interval in [0.0, -1.0, f64::INFINITY, f64::NAN, 1e300] {
+            assert!(wrap(Some(interval), None, None).is_err(), "interval {}", interval);
+        }
+        for jitter in [-1.0, f64::INFINITY, f64::NEG_INFINITY, f64::NAN, 1e300] {
+            assert!(wrap(Some(600.0), Some(jitter), None).is_err(), "jitter {}", jitter);
+            assert!(wrap(None, Some(jitter), Some("* * * * *")).is_err(), "jitter {}", jitter);
+        }
+    }
+
+    #[test]
+    fn siblings_are_spread_evenly() {
+        let offsets: Vec<_> = (0..50).map(|slot| offset(600.0, slot, 50)).collect();
+
+        assert_eq!(offsets[0], Duration::ZERO);
+        assert_eq!(offsets[49], Duration::from_secs(588));
+        for pair in offsets.windows(2) {
+            assert_eq!(pair[1] - pair[0], Duration::from_secs(12));
+        }
+
+        assert_eq!(offset(60.0, 0, 0), Duration::ZERO);
+        assert_eq!(offset(CRON_SPREAD, 1, 2), Duration::from_secs(30));
+    }
+}
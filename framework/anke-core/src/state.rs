@@ -1,30 +1,106 @@
-use rusqlite::{params, Connection};
+use rusqlite::{params, Connection, OptionalExtension, Transaction, TransactionBehavior};
+use serde::{de::DeserializeOwned, Serialize};
 
-use crate::log;
+use crate::{error, http::Validators, log, migrations, Error, Http, HttpConfig};
 use std::fmt;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-#[derive(Debug)]
+/// the database, queries run on the blocking pool so they never hold up the async workers;
+/// there is one connection, so they take turns
+#[derive(Clone)]
+pub(crate) struct Db(Arc<Mutex<Connection>>);
+
+impl fmt::Debug for Db {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Db").finish()
+    }
+}
+
+impl Db {
+    fn open(path: String) -> error::Result<Self> {
+        let conn = Connection::open(path)?;
+
+        // so the file can be looked at, e.g. with the sqlite3 shell, while we are writing to it
+        let mode: String = conn.query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))?;
+        log::debug!("Database is in {} mode", mode);
+        conn.busy_timeout(Duration::from_secs(5))?;
+
+        Ok(Self(Arc::new(Mutex::new(conn))))
+    }
+
+    /// locks the connection, only meant for startup before anything async is running
+    fn blocking(&self) -> error::Result<std::sync::MutexGuard<'_, Connection>> {
+        self.0
+            .lock()
+            .map_err(|_| Error::Storage("database lock is poisoned".into()))
+    }
+
+    pub(crate) async fn run<T, F>(&self, f: F) -> error::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let db = self.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let conn = db.blocking()?;
+            Ok(f(&conn)?)
+        })
+        .await
+        .map_err(|e| Error::Storage(format!("database task failed: {}", e)))?
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct State {
-    db: Arc<Mutex<Connection>>,
+    db: Db,
+    http: Http,
 }
 
 impl State {
-    pub fn new(path: String) -> Self {
-        let conn = Connection::open(path).unwrap();
-        conn.execute("CREATE TABLE IF NOT EXISTS tokens ( token_group TEXT NOT NULL, token_id TEXT NOT NULL, token TEXT, UNIQUE(token_group, token_id) ON CONFLICT REPLACE  );", []).unwrap();
+    pub fn new(path: String) -> error::Result<Self> {
+        let db = Db::open(path)?;
+
+        let mut conn = db.blocking()?;
+        migrations::migrate(&mut conn)?;
+        conn.execute("DELETE FROM kv WHERE expires_at <= ?1", params![now() as i64])?;
+        drop(conn);
+
+        let http = Http::new(HttpConfig::default())?.with_validators(ValidatorLog { db: db.clone() });
+
+        Ok(Self { db, http })
+    }
+
+    /// makes everything built from this state share `http`
+    pub fn with_http(self, http: Http) -> Self {
+        let validators = ValidatorLog {
+            db: self.db.clone(),
+        };
 
         Self {
-            db: Arc::new(Mutex::new(conn)),
+            http: http.with_validators(validators),
+            ..self
         }
     }
 
+    pub fn http(&self) -> &Http {
+        &self.http
+    }
+
     pub fn storage_for(
         &self,
         group: impl Into<String>,
         id: impl Into<String>,
     ) -> TokenStorageConnection {
-        TokenStorageConnection::new(Arc::clone(&self.db), group.into(), id.into())
+        TokenStorageConnection::new(self.db.clone(), group.into(), id.into())
+    }
+
+    pub fn files_for(&self, sink: impl Into<String>) -> FileLog {
+        FileLog {
+            sink: sink.into(),
+            db: self.db.clone(),
+        }
     }
 }
 
@@ -32,39 +108,480 @@ impl State {
 pub struct TokenStorageConnection {
     group: String,
     id: String,
-    db: Arc<Mutex<Connection>>,
+    db: Db,
 }
 
 impl TokenStorageConnection {
-    pub(crate) fn new(db: Arc<Mutex<Connection>>, group: String, id: String) -> Self {
+    pub(crate) fn new(db: Db, group: String, id: String) -> Self {
         Self { group, id, db }
     }
 
-    pub fn fetch<T: fmt::Debug + From<String>>(&self) -> Option<T> {
-        if let Ok(db) = self.db.lock() {
-            let res: Option<String> = db
-                .query_row(
+    /// what is stored, `None` if there is nothing or it does not parse as a `T`
+    pub async fn fetch<T: fmt::Debug + TryFrom<String>>(&self) -> error::Result<Option<T>>
+    where
+        T::Error: fmt::Display,
+    {
+        let (group, id) = (self.group.clone(), self.id.clone());
+        let res: Option<String> = self
+            .db
+            .run(move |db| {
+                match db.query_row(
                     "SELECT token FROM tokens WHERE (token_group = ?1 AND token_id = ?2)",
-                    params![self.group, self.id],
+                    params![group, id],
                     |row| row.get(0),
-                )
-                .ok();
+                ) {
+                    Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                    res => res,
+                }
+            })
+            .await?;
 
-            log::debug!("Read {:?} from {}::{}", res, self.group, self.id);
-            res.map(|r| r.into())
-        } else {
-            None
-        }
+        log::debug!("Read {:?} from {}::{}", res, self.group, self.id);
+        Ok(res.and_then(|r| match T::try_from(r) {
+            Ok(token) => Some(token),
+            Err(e) => {
+                log::warn!("Ignoring what is stored at {}::{}: {}", self.group, self.id, e);
+                None
+            }
+        }))
     }
 
-    pub fn store<T: Into<String> + fmt::Debug>(&mut self, new: T) {
+    /// the single untyped token, kept for what was stored before [`TokenStorageConnection::set`] existed
+    pub async fn store<T: Into<String> + fmt::Debug>(&mut self, new: T) -> error::Result<()> {
         log::debug!("Storing {:?} at {}::{}", new, self.group, self.id);
-        if let Ok(db) = self.db.lock() {
-            db.execute(
-                "INSERT INTO tokens (token_group, token_id, token) VALUES (?1, ?2, ?3)",
-                params![self.group, self.id, new.into()],
-            )
-            .ok();
-        }
+
+        let (group, id, new) = (self.group.clone(), self.id.clone(), new.into());
+        self.db
+            .run(move |db| {
+                db.execute(
+                    "INSERT INTO tokens (token_group, token_id, token) VALUES (?1, ?2, ?3)",
+                    params![group, id, new],
+                )
+            })
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// only rows that have not expired yet
+const LIVE: &str = "(expires_at IS NULL OR expires_at > ?3)";
+
+/// any number of typed values under their own keys, stored as json
+impl TokenStorageConnection {
+    /// what is stored at `key`, `None` if there is nothing, it expired or is not a `T`
+    pub async fn get<T: DeserializeOwned>(&self, key: &str) -> error::Result<Option<T>> {
+        let (group, id, wanted) = (self.group.clone(), self.id.clone(), key.to_owned());
+        let raw: Option<String> = self
+            .db
+            .run(move |db| {
+                db.query_row(
+                    &format!("SELECT value FROM kv WHERE (kv_group = ?1 AND kv_id = ?2 AND key = ?4 AND {})", LIVE),
+                    params![group, id, now() as i64, wanted],
+                    |row| row.get(0),
+                )
+                .optional()
+            })
+            .await?;
+
+        Ok(raw.and_then(|raw| match serde_json::from_str(&raw) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                log::warn!("Ignoring {}::{}::{}: {}", self.group, self.id, key, e);
+                None
+            }
+        }))
+    }
+
+    pub async fn set<T: Serialize>(&self, key: &str, value: &T) -> error::Result<()> {
+        self.put(key, serde_json::to_string(value)?, None).await
+    }
+
+    /// like [`TokenStorageConnection::set`], but gone again after `ttl`
+    pub async fn set_with_ttl<T: Serialize>(&self, key: &str, value: &T, ttl: Duration) -> error::Result<()> {
+        // sqlite integers are signed, anything past that is forever anyways
+        let expires_at = now().saturating_add(ttl.as_secs().max(1)).min(i64::MAX as u64);
+        self.put(key, serde_json::to_string(value)?, Some(expires_at)).await
+    }
+
+    async fn put(&self, key: &str, raw: String, expires_at: Option<u64>) -> error::Result<()> {
+        log::debug!("Storing {} at {}::{}::{}", raw, self.group, self.id, key);
+
+        let (group, id, key) = (self.group.clone(), self.id.clone(), key.to_owned());
+        self.db
+            .run(move |db| {
+                db.execute(
+                    "INSERT INTO kv (kv_group, kv_id, key, value, expires_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![group, id, key, raw, expires_at.map(|e| e as i64)],
+                )
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// sets `key` to `new` only if it currently is `expected`, `None` meaning nothing is there,
+    /// returns whether it did; a value set with a ttl keeps expiring when it did before
+    pub async fn compare_and_swap<T: Serialize>(&self, key: &str, expected: Option<&T>, new: &T) -> error::Result<bool> {
+        let expected = expected.map(serde_json::to_string).transpose()?;
+        let new = serde_json::to_string(new)?;
+        let (group, id, key) = (self.group.clone(), self.id.clone(), key.to_owned());
+
+        // holding the write lock from the read on, so nothing can get in between,
+        // not even another process on the same file
+        self.db
+            .run(move |db| {
+                let tx = Transaction::new_unchecked(db, TransactionBehavior::Immediate)?;
+
+                let (current, expires_at): (Option<String>, Option<i64>) = tx
+                    .query_row(
+                        &format!(
+                            "SELECT value, expires_at FROM kv WHERE (kv_group = ?1 AND kv_id = ?2 AND key = ?4 AND {})",
+                            LIVE
+                        ),
+                        params![group, id, now() as i64, key],
+                        |row| Ok((Some(row.get(0)?), row.get(1)?)),
+                    )
+                    .optional()?
+                    .unwrap_or_default();
+
+                // compared as parsed json so formatting does not matter
+                let parse = |raw: &Option<String>| raw.as_deref().map(serde_json::from_str::<serde_json::Value>);
+                let same = match (parse(&current), parse(&expected)) {
+                    (None, None) => true,
+                    (Some(Ok(a)), Some(Ok(b))) => a == b,
+                    _ => false,
+                };
+
+                if !same {
+                    return Ok(false);
+                }
+
+                tx.execute(
+                    "INSERT INTO kv (kv_group, kv_id, key, value, expires_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![group, id, key, new, expires_at],
+                )?;
+                tx.commit()?;
+
+                Ok(true)
+            })
+            .await
+    }
+
+    /// removes `key`, returns whether there was anything
+    pub async fn delete(&self, key: &str) -> error::Result<bool> {
+        let (group, id, key) = (self.group.clone(), self.id.clone(), key.to_owned());
+        let n = self
+            .db
+            .run(move |db| {
+                db.execute(
+                    &format!("DELETE FROM kv WHERE (kv_group = ?1 AND kv_id = ?2 AND key = ?4 AND {})", LIVE),
+                    params![group, id, now() as i64, key],
+                )
+            })
+            .await?;
+
+        Ok(n > 0)
+    }
+
+    /// every key that is set, sorted
+    pub async fn keys(&self) -> error::Result<Vec<String>> {
+        let (group, id) = (self.group.clone(), self.id.clone());
+        self.db
+            .run(move |db| {
+                let mut stmt = db.prepare(&format!(
+                    "SELECT key FROM kv WHERE (kv_group = ?1 AND kv_id = ?2 AND {}) ORDER BY key",
+                    LIVE
+                ))?;
+                let rows = stmt.query_map(params![group, id, now() as i64], |row| row.get(0))?;
+
+                rows.collect()
+            })
+            .await
+    }
+
+    /// every key with its value, sorted by key, values that are not a `T` are left out with a warning
+    pub async fn list<T: DeserializeOwned>(&self) -> error::Result<Vec<(String, T)>> {
+        let (group, id) = (self.group.clone(), self.id.clone());
+        let rows: Vec<(String, String)> = self
+            .db
+            .run(move |db| {
+                let mut stmt = db.prepare(&format!(
+                    "SELECT key, value FROM kv WHERE (kv_group = ?1 AND kv_id = ?2 AND {}) ORDER BY key",
+                    LIVE
+                ))?;
+                let rows = stmt.query_map(params![group, id, now() as i64], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+                rows.collect()
+            })
+            .await?;
+
+        Ok(rows
+            .into_iter()
+            .filter_map(|(key, raw)| match serde_json::from_str(&raw) {
+                Ok(value) => Some((key, value)),
+                Err(e) => {
+                    log::warn!("Ignoring {}::{}::{}: {}", self.group, self.id, key, e);
+                    None
+                }
+            })
+            .collect())
+    }
+}
+
+/// the etags and modification dates pages came with
+#[derive(Debug, Clone)]
+pub(crate) struct ValidatorLog {
+    db: Db,
+}
+
+impl ValidatorLog {
+    pub(crate) async fn get(&self, url: &str) -> error::Result<Option<Validators>> {
+        let url = url.to_owned();
+
+        self.db
+            .run(move |db| {
+                match db.query_row(
+                    "SELECT etag, last_modified FROM http_validators WHERE url = ?1",
+                    params![url],
+                    |row| {
+                        Ok(Validators {
+                            url: url.clone(),
+                            etag: row.get(0)?,
+                            last_modified: row.get(1)?,
+                        })
+                    },
+                ) {
+                    Ok(validators) => Ok(Some(validators)),
+                    Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                    Err(e) => Err(e),
+                }
+            })
+            .await
+    }
+
+    pub(crate) async fn set(&self, validators: &Validators) -> error::Result<()> {
+        let validators = validators.clone();
+
+        self.db
+            .run(move |db| {
+                db.execute(
+                    "INSERT INTO http_validators (url, etag, last_modified) VALUES (?1, ?2, ?3)",
+                    params![validators.url, validators.etag, validators.last_modified],
+                )
+            })
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileRecord {
+    pub source: String,
+    pub key: String,
+    pub size: u64,
+    // seconds since the epoch
+    pub written_at: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// what a sink has written, so old files can be cleaned up again
+#[derive(Debug, Clone)]
+pub struct FileLog {
+    sink: String,
+    db: Db,
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl FileLog {
+    pub async fn record(&self, source: &str, key: &str, size: u64) -> error::Result<()> {
+        log::debug!("Recording {} ({} bytes) for {}::{}", key, size, self.sink, source);
+
+        let (sink, source, key) = (self.sink.clone(), source.to_owned(), key.to_owned());
+        self.db
+            .run(move |db| {
+                db.execute(
+                    "INSERT INTO files (sink, source, file_key, size, written_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![sink, source, key, size as i64, now() as i64],
+                )
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn forget(&self, key: &str) -> error::Result<()> {
+        let (sink, key) = (self.sink.clone(), key.to_owned());
+        self.db
+            .run(move |db| {
+                db.execute(
+                    "DELETE FROM files WHERE (sink = ?1 AND file_key = ?2)",
+                    params![sink, key],
+                )
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// up to `limit` of the files written for `source`, oldest first, after skipping `offset` of them
+    pub async fn oldest(&self, source: &str, limit: usize, offset: usize) -> error::Result<Vec<FileRecord>> {
+        let (sink, source) = (self.sink.clone(), source.to_owned());
+        self.db
+            .run(move |db| {
+                let mut stmt = db.prepare(
+                    "SELECT source, file_key, size, written_at FROM files WHERE (sink = ?1 AND source = ?2) ORDER BY written_at ASC, rowid ASC LIMIT ?3 OFFSET ?4",
+                )?;
+
+                let rows = stmt.query_map(params![sink, source, limit as i64, offset as i64], |row| {
+                    Ok(FileRecord {
+                        source: row.get(0)?,
+                        key: row.get(1)?,
+                        size: row.get::<_, i64>(2)? as u64,
+                        written_at: row.get::<_, i64>(3)? as u64,
+                    })
+                })?;
+
+                rows.collect()
+            })
+            .await
+    }
+
+    /// how many files and bytes there are for `source`
+    pub async fn usage_of(&self, source: &str) -> error::Result<Usage> {
+        let (sink, source) = (self.sink.clone(), source.to_owned());
+        self.db
+            .run(move |db| {
+                db.query_row(
+                    "SELECT COUNT(*), COALESCE(SUM(size), 0) FROM files WHERE (sink = ?1 AND source = ?2)",
+                    params![sink, source],
+                    |row| {
+                        Ok(Usage {
+                            files: row.get::<_, i64>(0)? as u64,
+                            bytes: row.get::<_, i64>(1)? as u64,
+                        })
+                    },
+                )
+            })
+            .await
+    }
+
+    /// how many files and bytes there are per source
+    pub async fn usage(&self) -> error::Result<Vec<(String, Usage)>> {
+        let sink = self.sink.clone();
+        self.db
+            .run(move |db| {
+                let mut stmt = db.prepare(
+                    "SELECT source, COUNT(*), SUM(size) FROM files WHERE sink = ?1 GROUP BY source ORDER BY source",
+                )?;
+
+                let rows = stmt.query_map(params![sink], |row| {
+                    Ok((
+                        row.get(0)?,
+                        Usage {
+                            files: row.get::<_, i64>(1)? as u64,
+                            bytes: row.get::<_, i64>(2)? as u64,
+                        },
+                    ))
+                })?;
+
+                rows.collect()
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kv() -> TokenStorageConnection {
+        State::new(":memory:".into()).unwrap().storage_for("test", "kv")
+    }
+
+    #[tokio::test]
+    async fn compares_and_swaps() {
+        let kv = kv();
+
+        assert!(kv.compare_and_swap("n", None, &1).await.unwrap());
+        assert!(!kv.compare_and_swap("n", None, &2).await.unwrap());
+        assert!(!kv.compare_and_swap("n", Some(&2), &3).await.unwrap());
+        assert_eq!(kv.get::<i32>("n").await.unwrap(), Some(1));
+
+        assert!(kv.compare_and_swap("n", Some(&1), &2).await.unwrap());
+        assert_eq!(kv.get::<i32>("n").await.unwrap(), Some(2));
+
+        // compared by what it means, not how it was written
+        kv.put("json", r#"{ "a": 1, "b": [2] }"#.into(), None).await.unwrap();
+        let expected = serde_json::json!({"b": [2], "a": 1});
+        assert!(kv.compare_and_swap("json", Some(&expected), &serde_json::json!(null)).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn expired_values_are_gone() {
+        let kv = kv();
+
+        kv.set_with_ttl("fresh", &"yes", Duration::from_secs(60)).await.unwrap();
+        kv.put("stale", "\"no\"".into(), Some(now() - 1)).await.unwrap();
+        kv.set_with_ttl("forever", &"ish", Duration::MAX).await.unwrap();
+
+        assert_eq!(kv.get::<String>("fresh").await.unwrap().as_deref(), Some("yes"));
+        assert_eq!(kv.get::<String>("stale").await.unwrap(), None);
+        assert_eq!(kv.get::<String>("forever").await.unwrap().as_deref(), Some("ish"));
+        assert_eq!(kv.keys().await.unwrap(), ["forever", "fresh"]);
+        assert!(!kv.delete("stale").await.unwrap());
+
+        // an expired value counts as nothing, and a swapped one keeps its ttl
+        assert!(kv.compare_and_swap("stale", None, &"again").await.unwrap());
+        assert!(kv.compare_and_swap("fresh", Some(&"yes"), &"still").await.unwrap());
+        let (group, id) = (kv.group.clone(), kv.id.clone());
+        let expiries: Vec<Option<i64>> = kv
+            .db
+            .run(move |db| {
+                let mut stmt = db.prepare("SELECT expires_at FROM kv WHERE kv_group = ?1 AND kv_id = ?2 ORDER BY key")?;
+                let rows = stmt.query_map(params![group, id], |row| row.get(0))?;
+                rows.collect()
+            })
+            .await
+            .unwrap();
+        assert_eq!(expiries[0], Some(i64::MAX));
+        assert!(expiries[1].unwrap() > now() as i64);
+        assert_eq!(expiries[2], None);
+    }
+
+    #[tokio::test]
+    async fn lists_and_deletes() {
+        let kv = kv();
+        let other = kv.db.clone();
+
+        kv.set("b", &2).await.unwrap();
+        kv.set("a", &1).await.unwrap();
+        kv.put("c", "not json".into(), None).await.unwrap();
+        TokenStorageConnection::new(other, "test".into(), "other".into())
+            .set("d", &4)
+            .await
+            .unwrap();
+
+        assert_eq!(kv.keys().await.unwrap(), ["a", "b", "c"]);
+        assert_eq!(kv.list::<i32>().await.unwrap(), [("a".into(), 1), ("b".into(), 2)]);
+        assert_eq!(kv.get::<i32>("c").await.unwrap(), None);
+        assert_eq!(kv.get::<String>("b").await.unwrap(), None);
+
+        assert!(kv.delete("a").await.unwrap());
+        assert!(!kv.delete("a").await.unwrap());
+        assert_eq!(kv.get::<i32>("a").await.unwrap(), None);
+        assert_eq!(kv.keys().await.unwrap(), ["b", "c"]);
     }
 }
@@ -0,0 +1,255 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::fs;
+
+pub type StorageResult<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+
+/// somewhere files end up, keys are relative `/` separated paths
+#[async_trait]
+pub trait Storage: fmt::Debug + Send + Sync {
+    async fn put(&self, key: &str, data: Vec<u8>) -> StorageResult<()>;
+
+    async fn exists(&self, key: &str) -> StorageResult<bool>;
+
+    async fn delete(&self, key: &str) -> StorageResult<()>;
+
+    /// moves the finished file at `path` to `key`, by default by reading it into memory
+    async fn put_file(&self, key: &str, path: &Path) -> StorageResult<()> {
+        let data = fs::read(path).await?;
+        self.put(key, data).await?;
+        fs::remove_file(path).await?;
+
+        Ok(())
+    }
+
+    /// like `put_file`, `meta` describes the file for backends that can keep it
+    /// next to the content, the others ignore it
+    async fn put_file_with_meta(&self, key: &str, path: &Path, _meta: &[(&str, String)]) -> StorageResult<()> {
+        self.put_file(key, path).await
+    }
+
+    /// where `key` lives if this is the local filesystem, lets callers write there directly
+    fn local_path(&self, _key: &str) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// files below a directory
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path_of(&self, key: &str) -> PathBuf {
+        self.root.join(key.trim_start_matches('/'))
+    }
+
+    /// where `path` is written to first so nobody sees half a file
+    fn part_of(path: &Path) -> PathBuf {
+        let mut part = path.to_owned().into_os_string();
+        part.push(".part");
+        part.into()
+    }
+
+    async fn create_parent(path: &Path) -> StorageResult<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, data: Vec<u8>) -> StorageResult<()> {
+        let path = self.path_of(key);
+        Self::create_parent(&path).await?;
+
+        let part = Self::part_of(&path);
+        fs::write(&part, data).await?;
+        fs::rename(&part, &path).await?;
+
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str) -> StorageResult<bool> {
+        match fs::metadata(self.path_of(key)).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> StorageResult<()> {
+        match fs::remove_file(self.path_of(key)).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn put_file(&self, key: &str, path: &Path) -> StorageResult<()> {
+        let dest = self.path_of(key);
+        Self::create_parent(&dest).await?;
+
+        // rename does not work across filesystems, a copy only ends up at dest once it is complete
+        if fs::rename(path, &dest).await.is_err() {
+            let part = Self::part_of(&dest);
+            if let Err(e) = fs::copy(path, &part).await {
+                fs::remove_file(&part).await.ok();
+                return Err(e.into());
+            }
+
+            fs::rename(&part, &dest).await?;
+            fs::remove_file(path).await?;
+        }
+
+        Ok(())
+    }
+
+    fn local_path(&self, key: &str) -> Option<PathBuf> {
+        Some(self.path_of(key))
+    }
+}
+
+/// keeps everything in memory, for tests
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    files: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        self.files.lock().ok()?.get(key).cloned()
+    }
+
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self
+            .files
+            .lock()
+            .map(|files| files.keys().cloned().collect())
+            .unwrap_or_default();
+        keys.sort();
+
+        keys
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn put(&self, key: &str, data: Vec<u8>) -> StorageResult<()> {
+        self.files
+            .lock()
+            .map_err(|_| "memory storage lock poisoned")?
+            .insert(key.to_owned(), data);
+
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str) -> StorageResult<bool> {
+        Ok(self
+            .files
+            .lock()
+            .map_err(|_| "memory storage lock poisoned")?
+            .contains_key(key))
+    }
+
+    async fn delete(&self, key: &str) -> StorageResult<()> {
+        self.files
+            .lock()
+            .map_err(|_| "memory storage lock poisoned")?
+            .remove(key);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn put_exists_delete(storage: &dyn Storage) {
+        assert!(!storage.exists("a/b.png").await.unwrap());
+
+        storage.put("a/b.png", b"first".to_vec()).await.unwrap();
+        assert!(storage.exists("a/b.png").await.unwrap());
+
+        storage.put("a/b.png", b"second".to_vec()).await.unwrap();
+        assert!(storage.exists("a/b.png").await.unwrap());
+
+        storage.delete("a/b.png").await.unwrap();
+        assert!(!storage.exists("a/b.png").await.unwrap());
+
+        // deleting what is not there is fine
+        storage.delete("a/b.png").await.unwrap();
+        storage.delete("never/was.png").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn memory_storage() {
+        let storage = MemoryStorage::new();
+        put_exists_delete(&storage).await;
+
+        storage.put("x.json", b"{}".to_vec()).await.unwrap();
+        assert_eq!(storage.get("x.json"), Some(b"{}".to_vec()));
+        assert_eq!(storage.get("y.json"), None);
+        assert_eq!(storage.keys(), ["x.json"]);
+        assert_eq!(storage.local_path("x.json"), None);
+    }
+
+    #[tokio::test]
+    async fn memory_storage_takes_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let part = dir.path().join("b.png.part");
+        fs::write(&part, b"data").await.unwrap();
+
+        let storage = MemoryStorage::new();
+        storage
+            .put_file_with_meta("a/b.png", &part, &[("tags", "x".into())])
+            .await
+            .unwrap();
+
+        assert_eq!(storage.get("a/b.png"), Some(b"data".to_vec()));
+        assert!(fs::metadata(&part).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn local_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(dir.path());
+        put_exists_delete(&storage).await;
+
+        storage.put("/a/c.png", b"data".to_vec()).await.unwrap();
+        assert_eq!(fs::read(dir.path().join("a/c.png")).await.unwrap(), b"data");
+        assert_eq!(storage.local_path("a/c.png"), Some(dir.path().join("a/c.png")));
+        // nothing half written is left behind
+        assert!(fs::metadata(dir.path().join("a/c.png.part")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn local_storage_moves_files_into_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let part = dir.path().join("staging.part");
+        fs::write(&part, b"data").await.unwrap();
+
+        let storage = LocalStorage::new(dir.path().join("root"));
+        storage.put_file("deep/down/d.png", &part).await.unwrap();
+
+        assert_eq!(fs::read(dir.path().join("root/deep/down/d.png")).await.unwrap(), b"data");
+        assert!(fs::metadata(&part).await.is_err());
+    }
+}
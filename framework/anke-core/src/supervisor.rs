@@ -0,0 +1,394 @@
+use crate::{error, log, Aggregator, Context, EntryBox, Error, PipelineResult, State, TokenStorageConnection};
+use async_trait::async_trait;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::time::{self, Instant};
+
+// in seconds, no wait is ever longer than this, whatever the policy says
+const MAX_DELAY: f64 = 30.0 * 24.0 * 60.0 * 60.0;
+
+// where the health of each aggregator is kept, by its name
+const HEALTH_GROUP: &str = "aggregator_health";
+const HEALTH_KEY: &str = "status";
+
+fn _produce_30_0() -> f64 {
+    30.0
+}
+
+fn _produce_an_hour() -> f64 {
+    60.0 * 60.0
+}
+
+fn _produce_5() -> u32 {
+    5
+}
+
+fn _produce_6_hours() -> f64 {
+    6.0 * 60.0 * 60.0
+}
+
+fn _produce_0_2() -> f64 {
+    0.2
+}
+
+/// how failing aggregators get backed off, all durations in seconds
+#[derive(Debug, Deserialize, Clone)]
+pub struct BackoffPolicy {
+    // the wait after the first failure, doubled with every one after that
+    #[serde(default = "_produce_30_0")]
+    pub base: f64,
+
+    #[serde(default = "_produce_an_hour")]
+    pub max: f64,
+
+    // after this many failures in a row an aggregator is parked
+    #[serde(default = "_produce_5")]
+    pub trip_after: u32,
+
+    // how long a parked aggregator waits before it is tried again
+    #[serde(default = "_produce_6_hours")]
+    pub probe_every: f64,
+
+    // waits get stretched or shrunk randomly by up to this fraction,
+    // so aggregators that failed together do not retry together
+    #[serde(default = "_produce_0_2")]
+    pub jitter: f64,
+}
+
+impl Default for BackoffPolicy {
+    fn default() -> Self {
+        Self {
+            base: _produce_30_0(),
+            max: _produce_an_hour(),
+            trip_after: _produce_5(),
+            probe_every: _produce_6_hours(),
+            jitter: _produce_0_2(),
+        }
+    }
+}
+
+impl BackoffPolicy {
+    /// refuses durations that are not a number of seconds and jitter outside of 0 to 1
+    pub fn validate(&self) -> error::Result<()> {
+        for (field, secs) in [("base", self.base), ("max", self.max), ("probe_every", self.probe_every)] {
+            if !secs.is_finite() || secs < 0.0 {
+                return Err(Error::parse(format!("backoff {} of {} seconds", field, secs)));
+            }
+        }
+
+        if !(0.0..=1.0).contains(&self.jitter) {
+            return Err(Error::parse(format!("backoff jitter of {}, it has to be between 0 and 1", self.jitter)));
+        }
+
+        Ok(())
+    }
+
+    /// how long to wait after `failures` failures in a row
+    pub fn delay(&self, failures: u32) -> Duration {
+        let secs = if failures >= self.trip_after {
+            self.probe_every
+        } else {
+            let exp = failures.saturating_sub(1).min(32) as i32;
+            (self.base * 2f64.powi(exp)).min(self.max)
+        };
+
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let factor = if jitter > 0.0 {
+            rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter)
+        } else {
+            1.0
+        };
+
+        // NaN fails every comparison, so it ends up as 0 instead of panicking
+        let secs = (secs * factor).clamp(0.0, MAX_DELAY);
+        Duration::from_secs_f64(if secs.is_nan() { 0.0 } else { secs })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", content = "failures", rename_all = "snake_case")]
+pub enum Health {
+    Ok,
+    // failed this many times in a row, waiting before trying again
+    BackingOff(u32),
+    // failed too often, only probed every now and then
+    Tripped(u32),
+}
+
+/// the last [`Health`] of an aggregator as it is kept in the database
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthStatus {
+    pub health: Health,
+
+    // unix timestamps of when it changed and, while failing, when it is tried next
+    pub at: u64,
+    pub retry_at: Option<u64>,
+}
+
+impl HealthStatus {
+    /// what was last stored for the aggregator called `name`, `None` if it never failed
+    pub async fn of(state: &State, name: &str) -> error::Result<Option<Self>> {
+        state.storage_for(HEALTH_GROUP, name).get(HEALTH_KEY).await
+    }
+}
+
+/// wraps an aggregator so that its failures only hold back itself, not the others
+pub struct Supervised {
+    name: String,
+    inner: Box<dyn Aggregator<Item = EntryBox, PipelineState = State>>,
+    policy: BackoffPolicy,
+    failures: u32,
+    retry_at: Option<Instant>,
+    status: TokenStorageConnection,
+}
+
+impl Supervised {
+    pub fn wrap(
+        name: String,
+        inner: Box<dyn Aggregator<Item = EntryBox, PipelineState = State>>,
+        policy: BackoffPolicy,
+        state: &State,
+    ) -> Box<dyn Aggregator<Item = EntryBox, PipelineState = State>> {
+        let status = state.storage_for(HEALTH_GROUP, &name);
+
+        Box::new(Self {
+            name,
+            inner,
+            policy,
+            failures: 0,
+            retry_at: None,
+            status,
+        })
+    }
+
+    pub fn health(&self) -> Health {
+        match self.failures {
+            0 => Health::Ok,
+            n if n >= self.policy.trip_after => Health::Tripped(n),
+            n => Health::BackingOff(n),
+        }
+    }
+
+    async fn failed(&mut self, why: &(dyn std::fmt::Display + Sync)) {
+        self.failures = self.failures.saturating_add(1);
+
+        let delay = self.policy.delay(self.failures);
+        // delays are capped, so this does not overflow
+        self.retry_at = Some(Instant::now() + delay);
+
+        match self.health() {
+            Health::Tripped(n) if n == self.policy.trip_after => log::error!(
+                "{} failed {} times in a row and is parked, probing it again in {:.0}s: {}",
+                self.name,
+                n,
+                delay.as_secs_f64(),
+                why
+            ),
+            Health::Tripped(n) => log::warn!(
+                "Probe of parked {} failed ({} in a row), next one in {:.0}s: {}",
+                self.name,
+                n,
+                delay.as_secs_f64(),
+                why
+            ),
+            _ => log::warn!(
+                "{} failed ({} in a row), backing off for {:.0}s: {}",
+                self.name,
+                self.failures,
+                delay.as_secs_f64(),
+                why
+            ),
+        }
+
+        let now = crate::state::now();
+        self.store_status(HealthStatus {
+            health: self.health(),
+            at: now,
+            retry_at: Some(now.saturating_add(delay.as_secs())),
+        })
+        .await;
+    }
+
+    async fn succeeded(&mut self) {
+        let failures = std::mem::take(&mut self.failures);
+        self.retry_at = None;
+
+        if failures > 0 {
+            log::info!("{} recovered after {} failures", self.name, failures);
+            self.store_status(HealthStatus {
+                health: Health::Ok,
+                at: crate::state::now(),
+                retry_at: None,
+            })
+            .await;
+        }
+    }
+
+    async fn store_status(&self, status: HealthStatus) {
+        if let Err(e) = self.status.set(HEALTH_KEY, &status).await {
+            log::warn!("Could not store the health of {}: {}", self.name, e);
+        }
+    }
+
+    /// waits out the backoff of the last failure, if there is one
+    async fn backoff(&self) {
+        if let Some(retry_at) = self.retry_at {
+            log::trace!("Waiting for {}, it is {:?}", self.name, self.health());
+            time::sleep_until(retry_at).await;
+        }
+    }
+}
+
+#[async_trait]
+impl Aggregator for Supervised {
+    type Item = EntryBox;
+    type PipelineState = State;
+
+    fn on_load(&mut self) {
+        self.inner.on_load();
+    }
+
+    async fn poll(
+        &mut self,
+        ctx: &mut Context<Self::Item, Self::PipelineState>,
+    ) -> PipelineResult<()> {
+        // whatever is in front of us gets polled again right away, so this has to wait
+        // rather than return early, which would have the pipeline spin until then
+        self.backoff().await;
+
+        match self.inner.poll(ctx).await {
+            Ok(()) => self.succeeded().await,
+            Err(why) => self.failed(&why).await,
+        }
+
+        // the failure has been dealt with, the pipeline does not need to know
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(config: &str) -> BackoffPolicy {
+        let policy: BackoffPolicy = toml::from_str(config).unwrap();
+        BackoffPolicy { jitter: 0.0, ..policy }
+    }
+
+    #[test]
+    fn delays_double_up_to_max() {
+        let policy = policy("base = 10.0\nmax = 35.0\ntrip_after = 5\nprobe_every = 100.0");
+
+        let delays: Vec<u64> = (1..=6).map(|n| policy.delay(n).as_secs()).collect();
+        assert_eq!(delays, [10, 20, 35, 35, 100, 100]);
+    }
+
+    #[test]
+    fn huge_delays_are_capped() {
+        let policy = policy("base = 1e300\nmax = 1e300\nprobe_every = 1e300");
+        assert_eq!(policy.delay(1), Duration::from_secs_f64(MAX_DELAY));
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs_f64(MAX_DELAY));
+
+        // even if nobody validated it
+        let broken = BackoffPolicy {
+            base: f64::NAN,
+            max: f64::NAN,
+            probe_every: f64::INFINITY,
+            ..BackoffPolicy::default()
+        };
+        assert_eq!(broken.delay(1), Duration::ZERO);
+        assert_eq!(broken.delay(u32::MAX), Duration::from_secs_f64(MAX_DELAY));
+        let _ = Instant::now() + broken.delay(u32::MAX);
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let policy = BackoffPolicy {
+            jitter: 0.5,
+            ..policy("base = 100.0")
+        };
+
+        for _ in 0..100 {
+            let secs = policy.delay(1).as_secs_f64();
+            assert!((50.0..=150.0).contains(&secs), "{}", secs);
+        }
+    }
+
+    struct Failing;
+
+    #[async_trait]
+    impl Aggregator for Failing {
+        type Item = EntryBox;
+        type PipelineState = State;
+
+        async fn poll(
+            &mut self,
+            _: &mut Context<Self::Item, Self::PipelineState>,
+        ) -> PipelineResult<()> {
+            unreachable!()
+        }
+    }
+
+    fn supervised(state: &State, policy: BackoffPolicy) -> Supervised {
+        Supervised {
+            name: "failing".into(),
+            inner: Box::new(Failing),
+            policy,
+            failures: 0,
+            retry_at: None,
+            status: state.storage_for(HEALTH_GROUP, "failing"),
+        }
+    }
+
+    #[tokio::test]
+    async fn failures_are_waited_out() {
+        let state = State::new(":memory:".into()).unwrap();
+        let mut supervised = supervised(&state, policy("base = 0.2"));
+
+        let started = Instant::now();
+        supervised.backoff().await;
+        assert!(started.elapsed() < Duration::from_millis(100));
+
+        supervised.failed(&"boom").await;
+        let started = Instant::now();
+        supervised.backoff().await;
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn health_is_stored_for_others_to_read() {
+        let state = State::new(":memory:".into()).unwrap();
+        let mut supervised = supervised(&state, policy("base = 60.0\ntrip_after = 2"));
+        assert_eq!(HealthStatus::of(&state, "failing").await.unwrap(), None);
+
+        supervised.failed(&"boom").await;
+        let status = HealthStatus::of(&state, "failing").await.unwrap().unwrap();
+        assert_eq!(status.health, Health::BackingOff(1));
+        assert_eq!(status.retry_at, Some(status.at + 60));
+
+        supervised.failed(&"boom").await;
+        let status = HealthStatus::of(&state, "failing").await.unwrap().unwrap();
+        assert_eq!(status.health, Health::Tripped(2));
+        assert_eq!(
+            serde_json::to_value(status.health).unwrap(),
+            serde_json::json!({ "state": "tripped", "failures": 2 })
+        );
+
+        supervised.succeeded().await;
+        let status = HealthStatus::of(&state, "failing").await.unwrap().unwrap();
+        assert_eq!(status.health, Health::Ok);
+        assert_eq!(status.retry_at, None);
+    }
+
+    #[test]
+    fn validates_what_it_is_given() {
+        let parse = |config: &str| toml::from_str::<BackoffPolicy>(config).unwrap().validate();
+
+        assert!(parse("").is_ok());
+        assert!(parse("max = 0.0\njitter = 1.0").is_ok());
+        for bad in ["max = inf", "base = nan", "probe_every = -1.0", "jitter = 1.5", "jitter = -0.1", "jitter = nan"] {
+            assert!(parse(bad).is_err(), "{}", bad);
+        }
+    }
+}
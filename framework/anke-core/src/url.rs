@@ -1,4 +1,4 @@
-use crate::reqwest;
+use crate::{reqwest, Error};
 
 use linked_hash_map::LinkedHashMap as HashMap;
 
@@ -19,10 +19,12 @@ impl Url {
     pub fn from_string_with_query(s: String) -> Self {
         let (base, query_raw) = s.split_at(s.find('?').unwrap_or(s.len()));
 
-        let query = query_raw[1..]
+        let query = query_raw
+            .trim_start_matches('?')
             .split('&')
-            .map(|e| e.split_at(e.find('=').unwrap_or(e.len())))
-            .map(|(k, v)| (Self::decode_part(k), Self::decode_part(&v[1..])))
+            .filter(|e| !e.is_empty())
+            .map(|e| e.split_once('=').unwrap_or((e, "")))
+            .map(|(k, v)| (Self::decode_part(k), Self::decode_part(v)))
             .collect();
 
         Self {
@@ -60,19 +62,24 @@ impl Url {
         &mut self.query
     }
 
-    pub fn into_url(&self) -> reqwest::Url {
-        self.into()
+    pub fn into_url(&self) -> Result<reqwest::Url, Error> {
+        self.try_into()
     }
 }
 
-impl From<&Url> for reqwest::Url {
-    fn from(url: &Url) -> Self {
-        Self::parse(&url.to_string()).unwrap()
+impl TryFrom<&Url> for reqwest::Url {
+    type Error = Error;
+
+    fn try_from(url: &Url) -> Result<Self, Error> {
+        let s = url.to_string();
+        Self::parse(&s).map_err(|e| Error::parse(format!("url {}: {}", s, e)))
     }
 }
 
-impl From<Url> for reqwest::Url {
-    fn from(url: Url) -> Self {
-        Self::from(&url)
+impl TryFrom<Url> for reqwest::Url {
+    type Error = Error;
+
+    fn try_from(url: Url) -> Result<Self, Error> {
+        Self::try_from(&url)
     }
 }
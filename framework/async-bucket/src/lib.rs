@@ -34,7 +34,7 @@ impl AsyncBucket {
         let mut lock = self.last_decrement.lock().unwrap();
 
         let now = Instant::now();
-        let elapsed = now.duration_since(*lock);
+        let elapsed = now.saturating_duration_since(*lock);
 
         let cur_lock = self.ctr.lock().unwrap();
         let cur = cur_lock.get();
@@ -59,6 +59,22 @@ impl AsyncBucket {
         cur_lock
     }
 
+    /// overwrites the counter with what a remote told us is left,
+    /// with the next refill happening in `refill_in`
+    pub fn sync(&self, remaining: usize, refill_in: Duration) {
+        let mut lock = self.last_decrement.lock().unwrap();
+        let cur_lock = self.ctr.lock().unwrap();
+
+        cur_lock.set(cmp::min(self.ctr_limit, remaining));
+        *lock = Instant::now() + refill_in.saturating_sub(self.rate);
+
+        tracing::debug!(
+            "Synced bucket to {}, refilling in {:?}",
+            cur_lock.get(),
+            refill_in
+        );
+    }
+
     pub fn try_take(&self, cnt: usize) -> Result<(), usize> {
         let lock = self.update();
 
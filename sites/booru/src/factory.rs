@@ -1,4 +1,4 @@
-use anke_core::{Aggregator, AggregatorFactory, EntryBox, State};
+use anke_core::{log, Aggregator, AggregatorFactory, EntryBox, RateLimit, ScheduleConfig, Scheduled, State};
 use serde::Deserialize;
 
 use crate::gelbooru::GelbooruAggregator;
@@ -10,9 +10,26 @@ fn _produce_n_1() -> isize {
     -1
 }
 
+/// a tag on its own, or with its own schedule
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum GelbooruTag {
+    Plain(String),
+    Scheduled {
+        tag: String,
+
+        #[serde(flatten)]
+        schedule: ScheduleConfig,
+    },
+}
+
 #[derive(Debug, Deserialize)]
 pub struct GelbooruConfig {
-    pub(crate) tags: Vec<String>,
+    pub(crate) tags: Vec<GelbooruTag>,
+
+    // for every tag that does not bring its own
+    #[serde(flatten)]
+    pub(crate) schedule: ScheduleConfig,
 
     #[serde(default = "_produce_16")]
     pub(crate) fresh_poll_limit: isize,
@@ -22,6 +39,12 @@ pub struct GelbooruConfig {
 
     #[serde(default)]
     pub(crate) tags_in_embed: bool,
+
+    // shared with everything else talking to gelbooru, including downloads from its cdn
+    pub(crate) rate_limit: Option<RateLimit>,
+
+    // overrides `proxy` in [main.http] for gelbooru
+    pub(crate) proxy: Option<String>,
 }
 
 pub struct GelbooruFactory;
@@ -34,13 +57,42 @@ impl AggregatorFactory for GelbooruFactory {
     fn build_aggregators(
         config: GelbooruConfig,
         state: &State,
-    ) -> Vec<Box<dyn Aggregator<Item = EntryBox, PipelineState = State>>> {
-
-        config
-            .tags
-            .into_iter()
-            .map(|t| GelbooruAggregator::new(t, state, config.fresh_poll_limit, config.poll_limit, config.tags_in_embed))
-            .collect()
+    ) -> Vec<(String, Box<dyn Aggregator<Item = EntryBox, PipelineState = State>>)> {
+        if let Some(limit) = &config.rate_limit {
+            if let Err(why) = state.http().limit("gelbooru.com", limit) {
+                log::error!("Not polling gelbooru: {}", why);
+                return vec![];
+            }
+        }
+
+        let http = match config.proxy.as_deref().map(|p| state.http().with_proxy(p)) {
+            Some(Ok(http)) => http,
+            Some(Err(why)) => {
+                log::error!("Not polling gelbooru, its proxy does not work: {}", why);
+                return vec![];
+            }
+            None => state.http().clone(),
+        };
+
+        let slots = config.tags.len();
+        let mut aggregators = Vec::new();
+
+        for (slot, tag) in config.tags.into_iter().enumerate() {
+            let (tag, schedule) = match tag {
+                GelbooruTag::Plain(tag) => (tag, config.schedule.clone()),
+                GelbooruTag::Scheduled { tag, schedule } => (tag, schedule.or(&config.schedule)),
+            };
+
+            let name = format!("gelbooru[{}]", tag);
+            let agg = GelbooruAggregator::new(tag, state, http.clone(), config.fresh_poll_limit, config.poll_limit, config.tags_in_embed);
+
+            match Scheduled::wrap(name.clone(), agg, &schedule, slot, slots) {
+                Ok(agg) => aggregators.push((name, agg)),
+                Err(why) => log::error!("Skipping {}: {}", name, why),
+            }
+        }
+
+        aggregators
     }
 }
 
@@ -59,7 +111,7 @@ impl AggregatorFactory for DanbooruFactory {
     fn build_aggregators(
         config: DanbooruConfig,
         _state: &State,
-    ) -> Vec<Box<dyn Aggregator<Item = EntryBox, PipelineState = State>>> {
+    ) -> Vec<(String, Box<dyn Aggregator<Item = EntryBox, PipelineState = State>>)> {
         for _tag in config.tags {}
 
         Vec::new()
@@ -1,30 +1,42 @@
 use anke_core::{
-    reqwest, url::Url, Aggregator, Context, Entry, EntryBox, PipelineResult, State,
+    error, url::Url, Error, Aggregator, Http, Validators, Context, Entry, EntryBox, Media, PipelineResult, Rating, State, TagCategory,
     TokenStorageConnection,
 };
 
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 
 use std::collections::{HashMap, HashSet};
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 struct GelbooruId(usize);
 
+/// a tag from the page as data-tags spells it, the sidebar uses spaces and both escape html
+fn normalize_tag(raw: &str) -> String {
+    html_escape::decode_html_entities(raw.trim()).replace(' ', "_")
+}
+
 impl From<GelbooruId> for String {
     fn from(s: GelbooruId) -> String {
         format!("{}", s.0)
     }
 }
 
-impl From<&str> for GelbooruId {
-    fn from(s: &str) -> GelbooruId {
-        GelbooruId(s.parse().unwrap())
+impl TryFrom<&str> for GelbooruId {
+    type Error = Error;
+
+    fn try_from(s: &str) -> error::Result<GelbooruId> {
+        s.parse()
+            .map(GelbooruId)
+            .map_err(|_| Error::parse(format!("gelbooru id {:?}", s)))
     }
 }
 
-impl From<String> for GelbooruId {
-    fn from(s: String) -> GelbooruId {
-        GelbooruId(s.parse::<usize>().unwrap())
+impl TryFrom<String> for GelbooruId {
+    type Error = Error;
+
+    fn try_from(s: String) -> error::Result<GelbooruId> {
+        GelbooruId::try_from(s.as_str())
     }
 }
 
@@ -32,69 +44,88 @@ const PAGE_URL_BASE: &str = "https://gelbooru.com/index.php?page=post&s=list";
 
 #[derive(Debug)]
 struct GelbooruPage {
+    http: Http,
     url: Url,
     post_cnt: usize,
     posts: Vec<GelbooruId>,
+    // what the first page came with
+    validators: Validators,
 }
 
 impl GelbooruPage {
-    async fn tag(tag: &String) -> Self {
+    /// the first page for `tag`, `None` if `conditional` and it did not change since it was last remembered
+    async fn tag(http: &Http, tag: &String, conditional: bool) -> error::Result<Option<Self>> {
         let mut url = Url::from_string_with_query(PAGE_URL_BASE.to_owned());
 
         let headers = url.query_mut();
         headers.insert("tags".into(), tag.clone());
         headers.insert("pid".into(), "0".into());
 
+        let (raw, validators) = if conditional {
+            match http.text_if_changed(url.into_url()?).await? {
+                Some(page) => page,
+                None => return Ok(None),
+            }
+        } else {
+            (http.text(url.into_url()?).await?, Validators::default())
+        };
+
         let mut this = Self {
+            http: http.clone(),
             url,
             post_cnt: 0,
             posts: Vec::new(),
+            validators,
         };
 
-        this.fetch_page().await;
+        this.read(&raw)?;
+
+        Ok(Some(this))
+    }
+
+    async fn fetch_page(&mut self) -> error::Result<()> {
+        let raw = self.http.text(self.url.into_url()?).await?;
 
-        this
+        self.read(&raw)
     }
 
-    async fn fetch_page(&mut self) {
+    fn read(&mut self, raw: &str) -> error::Result<()> {
         lazy_static! {
             static ref ID_REGEX: Regex = Regex::new(r#"<a id="p(?P<id>\w+)""#).unwrap();
         };
 
-        let raw = reqwest::get(self.url.into_url())
-            .await
-            .unwrap()
-            .text()
-            .await
-            .unwrap();
-
-        let mut v: Vec<GelbooruId> = ID_REGEX
-            .captures_iter(&raw)
-            .map(|c| c["id"].into())
-            .collect();
+        let mut v = ID_REGEX
+            .captures_iter(raw)
+            .map(|c| GelbooruId::try_from(&c["id"]))
+            .collect::<error::Result<Vec<GelbooruId>>>()?;
 
         v.reverse();
         self.post_cnt = v.len();
         self.posts = v;
+
+        Ok(())
     }
 
-    async fn next_page(&mut self) {
+    async fn next_page(&mut self) -> error::Result<()> {
         let headers = self.url.query_mut();
-        let offset: usize = headers.get("pid".into()).unwrap().parse().unwrap();
+        let offset: usize = headers
+            .get("pid")
+            .ok_or_else(|| Error::parse("page url without a pid"))?
+            .parse()?;
         let offset = offset + self.post_cnt;
 
         headers.insert("pid".into(), offset.to_string());
 
-        self.fetch_page().await;
+        self.fetch_page().await
     }
 
-    async fn next_post(&mut self) -> Option<GelbooruId> {
+    async fn next_post(&mut self) -> error::Result<Option<GelbooruId>> {
         match self.posts.pop() {
             None => {
-                self.next_page().await;
-                self.posts.pop()
+                self.next_page().await?;
+                Ok(self.posts.pop())
             }
-            post => post,
+            post => Ok(post),
         }
     }
 }
@@ -103,6 +134,7 @@ impl GelbooruPage {
 pub struct GelbooruAggregator {
     pub(crate) tag: String,
     storage: TokenStorageConnection,
+    http: Http,
     fresh_poll_limit: isize,
     poll_limit: isize,
     tags_in_embed: bool,
@@ -112,6 +144,7 @@ impl GelbooruAggregator {
     pub(crate) fn new(
         tag: String,
         state: &State,
+        http: Http,
         fresh_poll_limit: isize,
         poll_limit: isize,
         tags_in_embed: bool,
@@ -120,17 +153,26 @@ impl GelbooruAggregator {
         Box::new(Self {
             tag,
             storage,
+            http,
             fresh_poll_limit,
             poll_limit,
             tags_in_embed
         })
     }
 
-    async fn scrape_posts_from_page(&self, mut limit: isize, until: GelbooruId) -> Vec<GelbooruId> {
-        let mut page = GelbooruPage::tag(&self.tag).await;
+    /// the posts newer than `until` and what to remember once they are dealt with,
+    /// nothing at all if the listing did not change since then
+    async fn scrape_posts_from_page(&self, mut limit: isize, until: GelbooruId, conditional: bool) -> error::Result<(Vec<GelbooruId>, Validators)> {
+        let mut page = match GelbooruPage::tag(&self.http, &self.tag, conditional).await? {
+            Some(page) => page,
+            None => {
+                debug!("[{}] Nothing changed", self.tag);
+                return Ok((Vec::new(), Validators::default()));
+            }
+        };
 
         let mut r = Vec::new();
-        while let Some(id) = page.next_post().await {
+        while let Some(id) = page.next_post().await? {
             limit -= 1;
             debug!("[{}] limit({}) > 0 = {}, id({}) > until({}) = {}", self.tag, limit, limit > 0, id.0, until.0, id > until);
             if limit >= 0 && id > until {
@@ -141,7 +183,7 @@ impl GelbooruAggregator {
             }
         }
 
-        r
+        Ok((r, page.validators))
     }
 }
 
@@ -156,14 +198,23 @@ impl Aggregator for GelbooruAggregator {
     ) -> PipelineResult<()> {
         info!("Polled {}", self.tag);
 
-        let (limit, mut newest) = {
-            match self.storage.fetch() {
-                Some(a) => (self.poll_limit, a),
-                None => (self.fresh_poll_limit, GelbooruId(0)),
+        let (limit, mut newest, known) = {
+            // tags from before the typed storage only have their newest post as the plain token
+            let stored = match self.storage.get("newest").await? {
+                Some(newest) => Some(newest),
+                None => self.storage.fetch().await?,
+            };
+
+            match stored {
+                Some(a) => (self.poll_limit, a, true),
+                None => (self.fresh_poll_limit, GelbooruId(0), false),
             }
         };
 
-        for post in self.scrape_posts_from_page(limit, newest).await {
+        // a tag we know nothing about always gets the whole listing
+        let (posts, validators) = self.scrape_posts_from_page(limit, newest, known).await?;
+
+        for post in posts {
             if post > newest {
                 newest = post;
             }
@@ -171,11 +222,12 @@ impl Aggregator for GelbooruAggregator {
             info!("Post: {}", post.0);
 
             ctx.sender
-                .send(Box::new(GelbooruEntry::fetch_from_id(post, self.tags_in_embed).await?))
+                .send(Box::new(GelbooruEntry::fetch_from_id(&self.http, post, &self.tag, self.tags_in_embed).await?))
                 .await;
         }
 
-        self.storage.store(newest);
+        self.storage.set("newest", &newest).await?;
+        self.http.remember(&validators).await?;
 
         Ok(())
     }
@@ -183,64 +235,145 @@ impl Aggregator for GelbooruAggregator {
 
 #[derive(Debug)]
 struct GelbooruEntry {
+    id: GelbooruId,
+    query: String,
     tags: HashSet<String>,
     image_url: Option<String>,
+    size: Option<(u32, u32)>,
     post_url: String,
     artist: Option<String>,
     characters: HashSet<String>,
+    categories: HashMap<TagCategory, HashSet<String>>,
+    rating: Rating,
     tags_in_embed: bool,
 }
 
 impl GelbooruEntry {
-    pub async fn fetch_from_id(id: GelbooruId, tags_in_embed: bool) -> reqwest::Result<Self> {
+    pub async fn fetch_from_id(http: &Http, id: GelbooruId, query: &str, tags_in_embed: bool) -> error::Result<Self> {
         let url = format!(
             "https://gelbooru.com/index.php?page=post&s=view&id={}",
             id.0
         );
 
-        let raw = reqwest::get(&url).await?.text().await?;
+        let raw = http.text(&url).await?;
 
-        Ok(Self::extract_info(url, raw, tags_in_embed))
+        Self::extract_info(id, url, query.to_owned(), raw, tags_in_embed)
     }
 
-    fn extract_info(post_url: String, raw: String, tags_in_embed: bool) -> Self {
+    fn extract_info(id: GelbooruId, post_url: String, query: String, raw: String, tags_in_embed: bool) -> error::Result<Self> {
         lazy_static! {
             static ref IMAGE_URL_REG: Regex = Regex::new(r#"image\.attr\('src','(?P<url>.+)'\);"#).unwrap();
             static ref TAGS_REG: Regex = Regex::new(r#"data-tags="(?P<tags>(\s?([^\s"])*\s?)*)"#).unwrap();
-            static ref ARTIST_REG: Regex = Regex::new(r#"class="tag-type-artist"><span class="sm-hidden"><a href=".+?">\?</a> </span><a href=".+?">(?P<artist>.+?)</a>"#).unwrap();
-            static ref CHARACTERS_REGEX: Regex = Regex::new(r#"class="tag-type-character"><span class="sm-hidden"><a href=".+?">\?</a> </span><a href=".+?">(?P<character>.+?)</a>"#).unwrap();
+            static ref TAG_TYPE_REG: Regex = Regex::new(r#"class="tag-type-(?P<kind>\w+)"><span class="sm-hidden"><a href=".+?">\?</a> </span><a href=".+?">(?P<tag>.+?)</a>"#).unwrap();
+            static ref SIZE_REG: Regex = Regex::new(r#"<li>Size: (?P<width>\d+)x(?P<height>\d+)</li>"#).unwrap();
+            static ref RATING_REG: Regex = Regex::new(r#"<li>Rating: (?P<rating>\w+)</li>"#).unwrap();
         }
 
         let image_url = IMAGE_URL_REG.captures(&raw).map(|c| c["url"].to_owned());
 
+        let size = SIZE_REG
+            .captures(&raw)
+            .and_then(|c| Some((c["width"].parse().ok()?, c["height"].parse().ok()?)));
+
         let tags = match TAGS_REG.captures(&raw) {
-            Some(cap) => cap["tags"].split_whitespace().map(str::to_string).collect(),
+            Some(cap) => cap["tags"].split_whitespace().map(normalize_tag).collect(),
             None => HashSet::new(),
         };
 
-        let artist = ARTIST_REG.captures(&raw).map(|c| c["artist"].to_owned());
+        // every post has tags and a file, if neither can be found the markup moved on
+        if image_url.is_none() && tags.is_empty() {
+            return Err(Error::site_changed(format!("no image or tags found on {}", post_url)));
+        }
+
+        let mut artist = None;
+        let mut characters = HashSet::new();
+        let mut categories: HashMap<TagCategory, HashSet<String>> = HashMap::new();
+
+        for cap in TAG_TYPE_REG.captures_iter(&raw) {
+            let category = match TagCategory::parse(&cap["kind"]) {
+                Some(category) => category,
+                None => continue,
+            };
+
+            let tag = normalize_tag(&cap["tag"]);
+
+            match category {
+                TagCategory::Artist if artist.is_none() => artist = Some(tag.clone()),
+                TagCategory::Character => {
+                    characters.insert(tag.clone());
+                }
+                _ => (),
+            }
+
+            categories.entry(category).or_default().insert(tag);
+        }
 
-        let characters: HashSet<String> = CHARACTERS_REGEX
-            .captures_iter(&raw)
-            .map(|c| c["character"].to_owned())
-            .collect();
+        let rating = RATING_REG
+            .captures(&raw)
+            .map(|c| Rating::parse(&c["rating"]))
+            .unwrap_or_default();
 
-        Self {
+        Ok(Self {
+            id,
             post_url,
+            query,
             tags,
             image_url,
+            size,
             artist,
             characters,
+            categories,
+            rating,
             tags_in_embed
-        }
+        })
     }
 }
 
 impl Entry for GelbooruEntry {
+    fn source_name(&self) -> Option<String> {
+        Some("gelbooru".into())
+    }
+
+    fn source_tag(&self) -> Option<String> {
+        Some(self.query.clone())
+    }
+
+    fn id(&self) -> Option<String> {
+        Some(self.id.0.to_string())
+    }
+
+    // gelbooru names its files after their md5
+    fn md5(&self) -> Option<String> {
+        let name = self.image_url.as_ref()?.rsplit('/').next()?;
+        let stem = name.split('.').next()?;
+
+        if stem.len() == 32 && stem.chars().all(|c| c.is_ascii_hexdigit()) {
+            Some(stem.to_lowercase())
+        } else {
+            None
+        }
+    }
+
+    fn artist(&self) -> Option<String> {
+        self.artist.clone()
+    }
+
+    fn characters(&self) -> Option<&HashSet<String>> {
+        Some(&self.characters)
+    }
+
+    fn rating(&self) -> Rating {
+        self.rating
+    }
+
     fn tags(&self) -> Option<&HashSet<String>> {
         Some(&self.tags)
     }
 
+    fn tag_categories(&self) -> Option<&HashMap<TagCategory, HashSet<String>>> {
+        Some(&self.categories)
+    }
+
     fn title(&self) -> Option<String> {
         let mut chars = itertools::join(self.characters.iter().map(|c| format!("\"{}\"", c)), ",");
         if chars.is_empty() {
@@ -263,6 +396,18 @@ impl Entry for GelbooruEntry {
         self.image_url.clone()
     }
 
+    fn media(&self) -> Vec<Media> {
+        let media = match &self.image_url {
+            Some(url) => Media::new(url),
+            None => return vec![],
+        };
+
+        match self.size {
+            Some((width, height)) => vec![media.with_size(width, height)],
+            None => vec![media],
+        }
+    }
+
     fn build_extra_fields(&self) -> HashMap<String, String> {
         let mut map = HashMap::new();
 
@@ -280,3 +425,64 @@ impl Entry for GelbooruEntry {
         map
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sidebar(kind: &str, tag: &str) -> String {
+        format!(
+            r#"<li class="tag-type-{}"><span class="sm-hidden"><a href="index.php?page=wiki">?</a> </span><a href="index.php?page=post&amp;s=list&amp;tags=x">{}</a> <span>12</span></li>"#,
+            kind, tag
+        )
+    }
+
+    fn page() -> String {
+        [
+            r#"<section class="image-container" data-id="1" data-tags=" hatsune_miku jack-o&#039;-lantern some_one vocaloid ">"#.to_owned(),
+            sidebar("artist", "some one"),
+            sidebar("character", "hatsune miku"),
+            sidebar("copyright", "vocaloid"),
+            sidebar("general", "jack-o&#039;-lantern"),
+            sidebar("bogus", "ignored"),
+            "<li>Size: 100x200</li>".to_owned(),
+            "<li>Rating: Sensitive</li>".to_owned(),
+            "image.attr('src','https://img3.gelbooru.com/images/ab/cd/abcd.png');".to_owned(),
+        ]
+        .join("\n")
+    }
+
+    fn extract(raw: String) -> error::Result<GelbooruEntry> {
+        GelbooruEntry::extract_info(GelbooruId(1), "https://gelbooru.com/post/1".into(), "query".into(), raw, false)
+    }
+
+    #[test]
+    fn sidebar_tags_are_spelled_like_data_tags() {
+        let entry = extract(page()).unwrap();
+
+        assert!(entry.tags.contains("jack-o'-lantern"));
+        assert_eq!(entry.artist.as_deref(), Some("some_one"));
+        assert_eq!(entry.characters, HashSet::from(["hatsune_miku".to_owned()]));
+        assert_eq!(entry.categories[&TagCategory::General], HashSet::from(["jack-o'-lantern".to_owned()]));
+
+        // every categorized tag is one of the tags
+        for tags in entry.categories.values() {
+            assert!(tags.is_subset(&entry.tags), "{:?} not in {:?}", tags, entry.tags);
+        }
+    }
+
+    #[test]
+    fn reads_the_rest_of_the_post() {
+        let entry = extract(page()).unwrap();
+
+        assert_eq!(entry.rating, Rating::Questionable);
+        assert_eq!(entry.size, Some((100, 200)));
+        assert_eq!(entry.image_url.as_deref(), Some("https://img3.gelbooru.com/images/ab/cd/abcd.png"));
+        assert!(!entry.categories.contains_key(&TagCategory::Meta));
+    }
+
+    #[test]
+    fn empty_pages_mean_the_site_changed() {
+        assert!(extract("<html></html>".into()).is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use anke_core::{
-    Aggregator, AggregatorFactory, EntryBox, OutputFilter, OutputFilterFactory, Pipeline, State,
+    error, Aggregator, AggregatorFactory, EntryBox, OutputFilter, OutputFilterFactory, Pipeline,
+    Http, State, Supervised,
 };
 
 use crate::config::Config;
@@ -12,33 +13,52 @@ pub struct App {
 }
 
 impl App {
-    pub fn new(config: Config) -> Self {
-        let state = State::new(config.main.database.clone());
+    pub fn new(config: Config) -> error::Result<Self> {
+        config.main.backoff.validate()?;
 
-        Self {
+        let http = Http::new(config.main.http.clone())?;
+        let state = State::new(config.main.database.clone())?.with_http(http);
+
+        Ok(Self {
             config,
             aggregators: Vec::new(),
             filters: Vec::new(),
             state,
+        })
+    }
+
+    /// the section `name` read as `T`, `None` with an error logged if that does not work
+    fn read_config<T: serde::de::DeserializeOwned>(kind: &str, name: &str, raw: &toml::Value) -> Option<T> {
+        match raw.clone().try_into() {
+            Ok(config) => Some(config),
+            Err(e) => {
+                error!("Skipping [{}.{}], its config is invalid: {}", kind, name, e);
+                None
+            }
         }
     }
 
+    /// runs until the pipeline is done or we are told to stop
     pub async fn run(self) -> () {
-        Pipeline::new(self.state)
+        let pipeline = Pipeline::new(self.state)
             .set_aggregators(self.aggregators)
             .set_output_filters(self.filters)
-            .setup_and_run()
-            .await
+            .setup_and_run();
+
+        anke_core::tokio::select! {
+            _ = pipeline => (),
+            _ = anke_core::tokio::signal::ctrl_c() => info!("Shutting down"),
+        }
     }
 
     pub fn register_aggregator_factory<F: AggregatorFactory>(mut self) -> Self {
         let name = <F as AggregatorFactory>::NAME;
 
-        if let Some(config) = self.config.sources.get(name) {
-            let config: <F as AggregatorFactory>::Config =
-                toml::from_str(&toml::to_string(config).unwrap()).unwrap();
-
-            for mut agg in <F as AggregatorFactory>::build_aggregators(config, &self.state) {
+        let config = self.config.sources.get(name);
+        if let Some(config) = config.and_then(|c| Self::read_config::<F::Config>("sources", name, c)) {
+            let aggregators = <F as AggregatorFactory>::build_aggregators(config, &self.state);
+            for (name, agg) in aggregators {
+                let mut agg = Supervised::wrap(name, agg, self.config.main.backoff.clone(), &self.state);
                 agg.on_load();
                 self.aggregators.push(agg);
             }
@@ -50,11 +70,9 @@ impl App {
     pub fn register_filter_factory<F: OutputFilterFactory>(mut self) -> Self {
         let name = <F as OutputFilterFactory>::NAME;
 
-        if let Some(config) = self.config.outputs.get(name) {
-            let config: <F as OutputFilterFactory>::Config =
-                toml::from_str(&toml::to_string(config).unwrap()).unwrap();
-
-            for mut f in <F as OutputFilterFactory>::build_filters(config) {
+        let config = self.config.outputs.get(name);
+        if let Some(config) = config.and_then(|c| Self::read_config::<F::Config>("outputs", name, c)) {
+            for mut f in <F as OutputFilterFactory>::build_filters(config, &self.state) {
                 f.on_load();
                 self.filters.push(f);
             }
@@ -1,11 +1,20 @@
 use std::collections::HashMap;
 
+use anke_core::{BackoffPolicy, HttpConfig};
 use serde::Deserialize;
 use toml::Value;
 
 #[derive(Deserialize, Debug)]
 pub struct MainConfig {
     pub database: String,
+
+    // how aggregators that keep failing get backed off
+    #[serde(default)]
+    pub backoff: BackoffPolicy,
+
+    // the client shared by all sources and outputs
+    #[serde(default)]
+    pub http: HttpConfig,
 }
 
 #[derive(Deserialize, Debug)]
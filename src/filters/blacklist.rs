@@ -1,4 +1,4 @@
-use anke_core::{async_trait, EntryBox, OutputFilter, OutputFilterFactory};
+use anke_core::{async_trait, EntryBox, OutputFilter, OutputFilterFactory, Rating, State, TagCategory};
 use serde::Deserialize;
 use std::collections::HashSet;
 
@@ -6,18 +6,22 @@ use std::collections::HashSet;
 pub struct BlacklistConfig {
     tags: Option<HashSet<String>>,
     names: Option<HashSet<String>>,
+    ratings: Option<HashSet<Rating>>,
 }
 
 #[derive(Debug)]
 pub struct BlacklistFilter {
     tags: HashSet<String>,
+    // from tags like "species:dragon", which only count in that category
+    categorized: Vec<(TagCategory, String)>,
     names: HashSet<String>,
+    ratings: HashSet<Rating>,
 }
 
 impl BlacklistFilter {
     fn swallow(&self, entry: EntryBox, tag: String) -> Option<EntryBox> {
         info!(
-            "Swallowed {:?} because it contained a banned tag/name: {}",
+            "Swallowed {:?} because it contained a banned tag/name/rating: {}",
             entry, tag
         );
 
@@ -45,6 +49,18 @@ impl OutputFilter for BlacklistFilter {
             }
         }
 
+        for (category, banned) in self.categorized.iter() {
+            if entry.tags_in(*category).is_some_and(|tags| tags.contains(banned)) {
+                let banned = format!("{}:{}", category.as_str(), banned);
+                return self.swallow(entry, banned);
+            }
+        }
+
+        if self.ratings.contains(&entry.rating()) {
+            let rating = entry.rating().as_str().to_owned();
+            return self.swallow(entry, rating);
+        }
+
         Some(entry)
     }
 }
@@ -54,10 +70,31 @@ impl OutputFilterFactory for BlacklistFilter {
 
     const NAME: &'static str = "blacklist";
 
-    fn build_filters(config: Self::Config) -> Vec<Box<dyn OutputFilter<Item = EntryBox>>> {
+    fn build_filters(
+        config: Self::Config,
+        _state: &State,
+    ) -> Vec<Box<dyn OutputFilter<Item = EntryBox>>> {
+        let mut tags = HashSet::new();
+        let mut categorized = Vec::new();
+
+        for tag in config.tags.unwrap_or_default() {
+            let category = tag
+                .split_once(':')
+                .and_then(|(category, rest)| Some((TagCategory::parse(category)?, rest)));
+
+            match category {
+                Some((category, rest)) => categorized.push((category, rest.to_owned())),
+                None => {
+                    tags.insert(tag);
+                }
+            }
+        }
+
         vec![Box::new(BlacklistFilter {
-            tags: config.tags.unwrap_or_default(),
+            tags,
+            categorized,
             names: config.names.unwrap_or_default(),
+            ratings: config.ratings.unwrap_or_default(),
         })]
     }
 }
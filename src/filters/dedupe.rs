@@ -1,4 +1,4 @@
-use anke_core::{async_trait, EntryBox, OutputFilter, OutputFilterFactory};
+use anke_core::{async_trait, EntryBox, OutputFilter, OutputFilterFactory, State};
 use serde::Deserialize;
 use std::collections::HashSet;
 
@@ -24,15 +24,16 @@ impl OutputFilter for DedupeFilter {
     type Item = EntryBox;
 
     async fn filter(&mut self, entry: EntryBox) -> Option<EntryBox> {
-        if let Some(url) = &entry.content_url() {
-            if self.memory.contains(url) {
-                info!("Dropped recently seen duplicate: {:?}", entry);
-                return None;
-            } else {
-                self.memory.insert(url.clone());
-            }
+        let urls: Vec<String> = entry.media().into_iter().map(|m| m.url).collect();
+
+        // only a duplicate if every file of it has been seen before
+        if !urls.is_empty() && urls.iter().all(|url| self.memory.contains(url)) {
+            info!("Dropped recently seen duplicate: {:?}", entry);
+            return None;
         }
 
+        self.memory.extend(urls);
+
         Some(entry)
     }
 }
@@ -42,7 +43,10 @@ impl OutputFilterFactory for DedupeFilter {
 
     const NAME: &'static str = "dedupe";
 
-    fn build_filters(config: Self::Config) -> Vec<Box<dyn OutputFilter<Item = EntryBox>>> {
+    fn build_filters(
+        config: Self::Config,
+        _state: &State,
+    ) -> Vec<Box<dyn OutputFilter<Item = EntryBox>>> {
         vec![Box::new(DedupeFilter::new(config))]
     }
 }
@@ -0,0 +1,291 @@
+use anke_core::{
+    async_bucket::AsyncBucket,
+    log, reqwest,
+    serde_json::{self, Value},
+    EntryBox, MediaKind, Rating,
+};
+use handlebars::Handlebars;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const DEFAULT_COLOR: u32 = 10034204;
+
+// discord shows the images of up to this many embeds sharing a url as one gallery
+const MAX_GALLERY: usize = 4;
+
+// discord refuses messages whose embeds have more text than this taken together
+pub(crate) const MAX_EMBED_CHARS: usize = 6000;
+
+lazy_static! {
+    static ref BUCKETS: Mutex<HashMap<String, Arc<AsyncBucket>>> = Mutex::new(HashMap::new());
+}
+
+/// how much of `embed` counts towards [`MAX_EMBED_CHARS`], going by what discord counts
+pub(crate) fn embed_len(embed: &Value) -> usize {
+    let len = |v: &Value| v.as_str().map_or(0, |s| s.chars().count());
+
+    let fields: usize = embed["fields"]
+        .as_array()
+        .map(|fields| fields.iter().map(|f| len(&f["name"]) + len(&f["value"])).sum())
+        .unwrap_or(0);
+
+    len(&embed["title"])
+        + len(&embed["description"])
+        + len(&embed["footer"]["text"])
+        + len(&embed["author"]["name"])
+        + fields
+}
+
+/// the bucket shared by everything posting into `route`, e.g. a webhook or a channel
+pub(crate) fn bucket_for(route: &str) -> Arc<AsyncBucket> {
+    let mut buckets = BUCKETS.lock().unwrap();
+
+    Arc::clone(buckets.entry(route.to_owned()).or_insert_with(|| {
+        // discord usually allows 5 requests per 2 seconds on a route,
+        // the real numbers get synced from the response headers
+        Arc::new(AsyncBucket::new(Duration::from_millis(400), 5).init(5))
+    }))
+}
+
+/// keeps `bucket` in line with what discord says is left
+pub(crate) fn sync_ratelimit(bucket: &AsyncBucket, res: &reqwest::Response) {
+    let header = |name: &str| {
+        res.headers()
+            .get(name)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.parse::<f64>().ok())
+    };
+
+    if let (Some(remaining), Some(reset_after)) = (
+        header("x-ratelimit-remaining"),
+        header("x-ratelimit-reset-after"),
+    ) {
+        bucket.sync(remaining as usize, Duration::from_secs_f64(reset_after.max(0.0)));
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageMode {
+    Full,
+    Thumbnail,
+    None,
+}
+
+/// what an embed looks like, every string field is a handlebars template over the entry
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct EmbedTemplate {
+    title: Option<String>,
+    description: Option<String>,
+    color: Option<u32>,
+
+    // colors by source name, e.g. { gelbooru = 255 }
+    #[serde(default)]
+    colors: HashMap<String, u32>,
+
+    // colors by rating, these win over `colors`, e.g. { explicit = 16711680 }
+    #[serde(default)]
+    rating_colors: HashMap<Rating, u32>,
+
+    author: Option<String>,
+    author_url: Option<String>,
+    author_icon_url: Option<String>,
+    footer: Option<String>,
+    footer_icon_url: Option<String>,
+    timestamp: Option<bool>,
+    image: Option<ImageMode>,
+}
+
+impl EmbedTemplate {
+    /// fills everything not set in `self` from `defaults`
+    pub(crate) fn or(self, defaults: &EmbedTemplate) -> EmbedTemplate {
+        let mut colors = defaults.colors.clone();
+        colors.extend(self.colors);
+
+        let mut rating_colors = defaults.rating_colors.clone();
+        rating_colors.extend(self.rating_colors);
+
+        EmbedTemplate {
+            title: self.title.or_else(|| defaults.title.clone()),
+            description: self.description.or_else(|| defaults.description.clone()),
+            color: self.color.or(defaults.color),
+            colors,
+            rating_colors,
+            author: self.author.or_else(|| defaults.author.clone()),
+            author_url: self.author_url.or_else(|| defaults.author_url.clone()),
+            author_icon_url: self.author_icon_url.or_else(|| defaults.author_icon_url.clone()),
+            footer: self.footer.or_else(|| defaults.footer.clone()),
+            footer_icon_url: self.footer_icon_url.or_else(|| defaults.footer_icon_url.clone()),
+            timestamp: self.timestamp.or(defaults.timestamp),
+            image: self.image.or(defaults.image),
+        }
+    }
+
+    fn templates(&self) -> [(&'static str, &Option<String>); 7] {
+        [
+            ("title", &self.title),
+            ("description", &self.description),
+            ("author", &self.author),
+            ("author_url", &self.author_url),
+            ("author_icon_url", &self.author_icon_url),
+            ("footer", &self.footer),
+            ("footer_icon_url", &self.footer_icon_url),
+        ]
+    }
+}
+
+/// renders entries into embeds following an `EmbedTemplate`
+pub(crate) struct EmbedBuilder {
+    name: String,
+    embed: EmbedTemplate,
+    templates: Handlebars<'static>,
+}
+
+impl EmbedBuilder {
+    /// `extra` are more templates that can be rendered by name later on
+    pub(crate) fn new(
+        name: String,
+        embed: EmbedTemplate,
+        extra: &[(&str, &Option<String>)],
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut templates = Handlebars::new();
+        templates.register_escape_fn(handlebars::no_escape);
+
+        for (key, template) in embed.templates().iter().chain(extra.iter()) {
+            if let Some(template) = template {
+                templates.register_template_string(key, template)?;
+            }
+        }
+
+        Ok(Self {
+            name,
+            embed,
+            templates,
+        })
+    }
+
+    pub(crate) fn image_mode(&self) -> ImageMode {
+        self.embed.image.unwrap_or(ImageMode::Full)
+    }
+
+    pub(crate) fn render(&self, name: &str, context: &Value) -> Option<Value> {
+        if !self.templates.has_template(name) {
+            return None;
+        }
+
+        match self.templates.render(name, context) {
+            Ok(s) if s.is_empty() => None,
+            Ok(s) => Some(s.into()),
+            Err(why) => {
+                log::error!("Failed rendering {} for {}: {}", name, self.name, why);
+                None
+            }
+        }
+    }
+
+    fn color_for(&self, entry: &EntryBox) -> u32 {
+        self.embed
+            .rating_colors
+            .get(&entry.rating())
+            .copied()
+            .or_else(|| {
+                entry
+                    .source_name()
+                    .and_then(|source| self.embed.colors.get(&source).copied())
+            })
+            .or(self.embed.color)
+            .unwrap_or(DEFAULT_COLOR)
+    }
+
+    /// the embed for `entry` followed by image only embeds for the rest of its images,
+    /// which discord shows together with the first one as a gallery
+    pub(crate) fn build_embeds(&self, entry: &EntryBox) -> Vec<Value> {
+        let mut embeds = vec![self.build_embed(entry)];
+
+        let url = match entry.title_url() {
+            Some(url) if self.image_mode() == ImageMode::Full => url,
+            _ => return embeds,
+        };
+
+        let shown = entry.image_url();
+        let rest = entry
+            .media()
+            .into_iter()
+            .filter(|m| matches!(m.kind, MediaKind::Image | MediaKind::Animation))
+            .filter(|m| Some(&m.url) != shown.as_ref())
+            .take(MAX_GALLERY - 1);
+
+        for media in rest {
+            embeds.push(serde_json::json!({
+                "url": url,
+                "image": { "url": media.url },
+            }));
+        }
+
+        embeds
+    }
+
+    pub(crate) fn build_embed(&self, entry: &EntryBox) -> Value {
+        let context = entry.to_json();
+
+        let mut embed = serde_json::json!({
+            "title": self
+                .render("title", &context)
+                .or_else(|| entry.title().map(|t| t.into()))
+                .unwrap_or(Value::Null),
+            "color": self.color_for(entry),
+            "url": entry.title_url().map(|t| t.into()).unwrap_or(Value::Null),
+        });
+
+        if let Some(description) = self.render("description", &context) {
+            embed["description"] = description;
+        }
+
+        if let Some(author) = self.render("author", &context) {
+            embed["author"] = serde_json::json!({ "name": author });
+            if let Some(url) = self.render("author_url", &context) {
+                embed["author"]["url"] = url;
+            }
+            if let Some(icon_url) = self.render("author_icon_url", &context) {
+                embed["author"]["icon_url"] = icon_url;
+            }
+        }
+
+        if let Some(footer) = self.render("footer", &context) {
+            embed["footer"] = serde_json::json!({ "text": footer });
+            if let Some(icon_url) = self.render("footer_icon_url", &context) {
+                embed["footer"]["icon_url"] = icon_url;
+            }
+        }
+
+        if self.embed.timestamp.unwrap_or(false) {
+            embed["timestamp"] = chrono::Utc::now().to_rfc3339().into();
+        }
+
+        if let Some(url) = entry.image_url() {
+            match self.image_mode() {
+                ImageMode::Full => embed["image"] = serde_json::json!({ "url": url }),
+                ImageMode::Thumbnail => embed["thumbnail"] = serde_json::json!({ "url": url }),
+                ImageMode::None => (),
+            }
+        }
+
+        embed["fields"] = Value::Array({
+            let mut fields = Vec::new();
+            for (name, body) in entry.build_extra_fields() {
+                fields.push(serde_json::json!(
+                    {
+                        "name": name,
+                        "value": body
+                    }
+                ))
+            }
+            fields
+        });
+
+        embed
+    }
+}
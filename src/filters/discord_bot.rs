@@ -0,0 +1,496 @@
+use super::discord::{bucket_for, sync_ratelimit, EmbedBuilder, EmbedTemplate};
+use anke_core::{
+    async_trait, error, http, log,
+    reqwest::{self, Method, StatusCode},
+    serde_json::{self, Value},
+    tokio::time,
+    EntryBox, Error, Http, OutputFilter, OutputFilterFactory, Rating, State,
+};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+// discord cuts thread names off after this many characters
+const MAX_THREAD_NAME: usize = 100;
+
+// what thread entries without a source tag go into on forum channels
+const UNTAGGED_THREAD: &str = "untagged";
+
+fn _produce_api_base() -> String {
+    "https://discord.com/api/v10".into()
+}
+
+fn _produce_a_week() -> u32 {
+    10080
+}
+
+fn encode_path_part(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelKind {
+    #[default]
+    Text,
+    Forum,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BotChannel {
+    id: String,
+
+    #[serde(default)]
+    kind: ChannelKind,
+
+    // post into one thread per source tag instead of the channel itself,
+    // forum channels always do this
+    #[serde(default)]
+    threads: bool,
+
+    // forum tag ids applied to new posts, by source tag
+    #[serde(default)]
+    forum_tags: HashMap<String, String>,
+
+    // overrides the global `reactions` for this channel
+    reactions: Option<Vec<String>>,
+
+    // only entries with these ratings go here, e.g. ["explicit"] for an nsfw channel
+    #[serde(default)]
+    ratings: HashSet<Rating>,
+
+    #[serde(flatten)]
+    embed: EmbedTemplate,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DiscordBotConfig {
+    token: String,
+
+    // where the discord REST api lives, can be pointed at a local mock
+    #[serde(default = "_produce_api_base")]
+    api_base: String,
+
+    // in minutes, one of 60, 1440, 4320 or 10080
+    #[serde(default = "_produce_a_week")]
+    auto_archive_duration: u32,
+
+    // reacted onto every message
+    #[serde(default)]
+    reactions: Vec<String>,
+
+    // defaults for every channel, each channel can override them
+    #[serde(default)]
+    embed: EmbedTemplate,
+
+    channels: HashMap<String, BotChannel>,
+}
+
+pub struct DiscordBotFilter {
+    dest: String,
+    channel: BotChannel,
+    builder: EmbedBuilder,
+    token: String,
+    api_base: String,
+    auto_archive_duration: u32,
+    reactions: Vec<String>,
+    http: Http,
+    state: State,
+}
+
+impl fmt::Debug for DiscordBotFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DiscordBotFilter")
+            .field("dest", &self.dest)
+            .finish()
+    }
+}
+
+impl DiscordBotFilter {
+    async fn call(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&Value>,
+    ) -> reqwest::Result<reqwest::Response> {
+        // discord limits per channel, so "/channels/<id>" is what shares a bucket
+        let route: Vec<&str> = path.split('/').take(3).collect();
+        let bucket = bucket_for(&format!("{}{}", self.api_base, route.join("/")));
+
+        loop {
+            bucket.take(1).await;
+
+            let mut req = self
+                .http
+                .request(method.clone(), format!("{}{}", self.api_base, path))
+                .header(reqwest::header::AUTHORIZATION, format!("Bot {}", self.token));
+
+            req = match body {
+                Some(body) => req.json(body),
+                // discord wants a length even on empty PUTs
+                None => req.header(reqwest::header::CONTENT_LENGTH, 0),
+            };
+
+            let res = self.http.send(req).await?;
+            sync_ratelimit(&bucket, &res);
+
+            if res.status() != StatusCode::TOO_MANY_REQUESTS {
+                return Ok(res);
+            }
+
+            // unlike on webhooks this is actually accurate for bots
+            let int = http::retry_after(res.json::<Value>().await?["retry_after"].as_f64(), 5.0);
+            log::warn!("Hit discord ratelimit on {}, sleeping for {:?}", path, int);
+            time::sleep(int).await;
+        }
+    }
+
+    /// posts `message` into `channel`, `None` if the channel does not exist (anymore)
+    async fn post(&self, channel: &str, message: &Value) -> reqwest::Result<Option<String>> {
+        let res = self
+            .call(Method::POST, &format!("/channels/{}/messages", channel), Some(message))
+            .await?;
+
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let js = res.error_for_status()?.json::<Value>().await?;
+        Ok(js["id"].as_str().map(str::to_owned))
+    }
+
+    fn thread_storage(&self, tag: &str) -> anke_core::TokenStorageConnection {
+        self.state
+            .storage_for("discord_bot_threads", format!("{}/{}", self.channel.id, tag))
+    }
+
+    /// creates the thread for `tag`, forum posts start out with `message` in them
+    /// and give back its id along with the thread
+    async fn create_thread(
+        &self,
+        tag: &str,
+        message: &Value,
+    ) -> error::Result<(String, Option<String>)> {
+        let name: String = tag.chars().take(MAX_THREAD_NAME).collect();
+
+        let mut body = serde_json::json!({
+            "name": name,
+            "auto_archive_duration": self.auto_archive_duration,
+        });
+
+        match self.channel.kind {
+            ChannelKind::Forum => {
+                body["message"] = message.clone();
+                if let Some(forum_tag) = self.channel.forum_tags.get(tag) {
+                    body["applied_tags"] = serde_json::json!([forum_tag]);
+                }
+            }
+            // a public thread
+            ChannelKind::Text => body["type"] = 11.into(),
+        }
+
+        let js = self
+            .call(
+                Method::POST,
+                &format!("/channels/{}/threads", self.channel.id),
+                Some(&body),
+            )
+            .await?
+            .error_for_status()?
+            .json::<Value>()
+            .await?;
+
+        let thread = match js["id"].as_str().filter(|id| !id.is_empty()) {
+            Some(thread) => thread.to_owned(),
+            None => return Err(Error::site_changed(format!("thread for {} without an id: {}", tag, js))),
+        };
+        log::info!("Created thread {} for {} in {}", thread, tag, self.dest);
+        if let Err(e) = self.thread_storage(tag).store(thread.clone()).await {
+            log::warn!("Could not remember thread {} for {}: {}", thread, tag, e);
+        }
+
+        // the starter message of a forum post shares its id with the thread
+        match self.channel.kind {
+            ChannelKind::Forum => Ok((thread.clone(), Some(thread))),
+            ChannelKind::Text => Ok((thread, None)),
+        }
+    }
+
+    /// posts `message` into the thread for `tag`, making one if there is none yet
+    /// or the one we knew about is gone
+    async fn post_to_thread(
+        &self,
+        tag: &str,
+        message: &Value,
+    ) -> error::Result<(String, Option<String>)> {
+        let known = self.thread_storage(tag).fetch::<String>().await.unwrap_or_else(|e| {
+            log::warn!("Could not look up the thread for {}: {}", tag, e);
+            None
+        });
+
+        if let Some(thread) = known {
+            if let Some(id) = self.post(&thread, message).await? {
+                return Ok((thread, Some(id)));
+            }
+
+            log::warn!("Thread {} for {} is gone, making a new one", thread, tag);
+        }
+
+        let (thread, id) = self.create_thread(tag, message).await?;
+        match id {
+            Some(id) => Ok((thread, Some(id))),
+            None => {
+                let id = self.post(&thread, message).await?;
+                Ok((thread, id))
+            }
+        }
+    }
+
+    async fn react(&self, channel: &str, message: &str) -> reqwest::Result<()> {
+        for reaction in &self.reactions {
+            self.call(
+                Method::PUT,
+                &format!(
+                    "/channels/{}/messages/{}/reactions/{}/@me",
+                    channel,
+                    message,
+                    encode_path_part(reaction)
+                ),
+                None,
+            )
+            .await?
+            .error_for_status()?;
+        }
+
+        Ok(())
+    }
+
+    async fn send(&self, entry: &EntryBox) -> error::Result<()> {
+        log::debug!("Sending {:?} into {}", entry, self.dest);
+
+        let message = serde_json::json!({
+            "embeds": self.builder.build_embeds(entry)
+        });
+
+        let tag = entry.source_tag();
+
+        let (channel, id) = match (self.channel.kind, tag) {
+            (ChannelKind::Forum, tag) => {
+                self.post_to_thread(tag.as_deref().unwrap_or(UNTAGGED_THREAD), &message)
+                    .await?
+            }
+            (ChannelKind::Text, Some(tag)) if self.channel.threads => {
+                self.post_to_thread(&tag, &message).await?
+            }
+            (ChannelKind::Text, _) => {
+                let id = self.post(&self.channel.id, &message).await?;
+                (self.channel.id.clone(), id)
+            }
+        };
+
+        match id {
+            Some(id) => Ok(self.react(&channel, &id).await?),
+            None => {
+                log::error!("Channel {} of {} does not exist", channel, self.dest);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl OutputFilter for DiscordBotFilter {
+    type Item = EntryBox;
+
+    async fn filter(&mut self, entry: EntryBox) -> Option<EntryBox> {
+        if !self.channel.ratings.is_empty() && !self.channel.ratings.contains(&entry.rating()) {
+            return Some(entry);
+        }
+
+        if let Err(e) = self.send(&entry).await {
+            log::error!("Error while posting into {}: {:?}", self.dest, e);
+        }
+
+        Some(entry)
+    }
+}
+
+impl OutputFilterFactory for DiscordBotFilter {
+    type Config = DiscordBotConfig;
+
+    const NAME: &'static str = "discord_bot";
+
+    fn build_filters(
+        config: Self::Config,
+        state: &State,
+    ) -> Vec<Box<dyn OutputFilter<Item = EntryBox>>> {
+        let mut filters: Vec<Box<dyn OutputFilter<Item = EntryBox>>> = Vec::new();
+
+        for (dest, mut channel) in config.channels {
+            let embed = std::mem::take(&mut channel.embed).or(&config.embed);
+
+            let builder = match EmbedBuilder::new(dest.clone(), embed, &[]) {
+                Ok(builder) => builder,
+                Err(why) => {
+                    log::error!("Skipping discord channel {}: {}", dest, why);
+                    continue;
+                }
+            };
+
+            filters.push(Box::new(DiscordBotFilter {
+                reactions: channel
+                    .reactions
+                    .clone()
+                    .unwrap_or_else(|| config.reactions.clone()),
+                dest,
+                channel,
+                builder,
+                token: config.token.clone(),
+                api_base: config.api_base.trim_end_matches('/').to_owned(),
+                auto_archive_duration: config.auto_archive_duration,
+                http: state.http().clone(),
+                state: state.clone(),
+            }));
+        }
+
+        filters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::{mock, Matcher};
+
+    #[derive(Debug)]
+    struct Tagged(&'static str);
+
+    impl anke_core::Entry for Tagged {
+        fn source_tag(&self) -> Option<String> {
+            Some(self.0.to_owned())
+        }
+    }
+
+    fn filter(state: &State, channel: &str, threads: bool) -> Box<dyn OutputFilter<Item = EntryBox>> {
+        let config: DiscordBotConfig = toml::from_str(&format!(
+            r#"
+                token = "T"
+                api_base = "{}"
+
+                [channels.test]
+                id = "{}"
+                threads = {}
+            "#,
+            mockito::server_url(),
+            channel,
+            threads
+        ))
+        .unwrap();
+
+        DiscordBotFilter::build_filters(config, state).pop().unwrap()
+    }
+
+    #[tokio::test]
+    async fn threads_are_made_once_and_reused() {
+        let create = mock("POST", "/channels/100/threads")
+            .match_body(Matcher::PartialJson(serde_json::json!({ "name": "cats", "type": 11 })))
+            .with_body(r#"{"id":"101"}"#)
+            .expect(1)
+            .create();
+        let post = mock("POST", "/channels/101/messages")
+            .match_header("authorization", "Bot T")
+            .with_body(r#"{"id":"102"}"#)
+            .expect(2)
+            .create();
+        let direct = mock("POST", "/channels/100/messages").expect(0).create();
+
+        let state = State::new(":memory:".into()).unwrap();
+        let mut filter = filter(&state, "100", true);
+        filter.filter(Box::new(Tagged("cats"))).await;
+        filter.filter(Box::new(Tagged("cats"))).await;
+
+        create.assert();
+        post.assert();
+        direct.assert();
+    }
+
+    #[tokio::test]
+    async fn gone_threads_are_replaced() {
+        let gone = mock("POST", "/channels/201/messages")
+            .with_status(404)
+            .expect(1)
+            .create();
+        let create = mock("POST", "/channels/200/threads")
+            .with_body(r#"{"id":"202"}"#)
+            .expect(1)
+            .create();
+        let post = mock("POST", "/channels/202/messages")
+            .with_body(r#"{"id":"203"}"#)
+            .expect(1)
+            .create();
+
+        let state = State::new(":memory:".into()).unwrap();
+        state
+            .storage_for("discord_bot_threads", "200/dogs")
+            .store("201".to_owned())
+            .await
+            .unwrap();
+
+        let mut filter = filter(&state, "200", true);
+        filter.filter(Box::new(Tagged("dogs"))).await;
+
+        gone.assert();
+        create.assert();
+        post.assert();
+
+        let known = state.storage_for("discord_bot_threads", "200/dogs").fetch::<String>().await;
+        assert_eq!(known.unwrap().as_deref(), Some("202"));
+    }
+
+    #[tokio::test]
+    async fn threads_without_an_id_are_not_kept() {
+        let create = mock("POST", "/channels/400/threads")
+            .with_body(r#"{"name":"fish"}"#)
+            .expect(1)
+            .create();
+        let nowhere = mock("POST", "/channels//messages").expect(0).create();
+
+        let state = State::new(":memory:".into()).unwrap();
+        let mut filter = filter(&state, "400", true);
+        filter.filter(Box::new(Tagged("fish"))).await;
+
+        create.assert();
+        nowhere.assert();
+
+        let known = state.storage_for("discord_bot_threads", "400/fish").fetch::<String>().await;
+        assert_eq!(known.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn ratelimits_are_waited_out() {
+        // a bogus retry_after must neither panic nor stall us
+        let limited = mock("POST", "/channels/300/messages")
+            .with_status(429)
+            .with_body(r#"{"retry_after":-1}"#)
+            .expect(1)
+            .create();
+        let post = mock("POST", "/channels/300/messages")
+            .with_body(r#"{"id":"301"}"#)
+            .expect(1)
+            .create();
+
+        let state = State::new(":memory:".into()).unwrap();
+        let mut filter = filter(&state, "300", false);
+        filter.filter(Box::new(Tagged("birds"))).await;
+
+        limited.assert();
+        post.assert();
+    }
+}
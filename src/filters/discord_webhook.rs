@@ -1,19 +1,183 @@
+use super::discord::{bucket_for, embed_len, sync_ratelimit, EmbedBuilder, EmbedTemplate, ImageMode, MAX_EMBED_CHARS};
+use super::media::{self, Process};
 use anke_core::{
+    async_bucket::AsyncBucket,
     async_trait,
-    log, reqwest,
+    http, log, reqwest,
     serde_json::{self, Value},
-    tokio::time,
-    EntryBox, OutputFilter, OutputFilterFactory,
+    tokio::{self, fs, sync::{mpsc, watch}, task::JoinHandle, time},
+    EntryBox, Http, Media, OutputFilter, OutputFilterFactory, Rating, State,
 };
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
 use std::fmt;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-pub struct DiscordWebhookFilter {
+// discord refuses messages with more embeds or files than this
+const MAX_EMBEDS: usize = 10;
+const MAX_FILES: usize = 10;
+
+fn _produce_2_0() -> f64 {
+    2.0
+}
+
+fn _produce_max_embeds() -> usize {
+    MAX_EMBEDS
+}
+
+fn _produce_8mb() -> usize {
+    8 * 1024 * 1024
+}
+
+lazy_static! {
+    // flipped once on shutdown, senders then send what they have right away
+    static ref SHUTDOWN: (watch::Sender<bool>, watch::Receiver<bool>) = watch::channel(false);
+    static ref SENDERS: Mutex<Vec<JoinHandle<()>>> = Mutex::new(Vec::new());
+}
+
+fn is_video(filename: &str) -> bool {
+    let ext = filename.rsplit('.').next().unwrap_or("").to_lowercase();
+
+    matches!(ext.as_str(), "mp4" | "webm" | "mov" | "mkv")
+}
+
+#[derive(Debug)]
+struct Attachment {
+    filename: String,
+    data: Vec<u8>,
+}
+
+/// one webhook message, possibly with files to upload alongside it
+#[derive(Debug)]
+struct Message {
+    body: Value,
+    files: Vec<Attachment>,
+}
+
+impl Message {
+    fn embeds(&self) -> usize {
+        self.body["embeds"].as_array().map(Vec::len).unwrap_or(0)
+    }
+
+    fn size(&self) -> usize {
+        self.files.iter().map(|f| f.data.len()).sum()
+    }
+
+    /// the text of all embeds taken together
+    fn text_len(&self) -> usize {
+        self.body["embeds"]
+            .as_array()
+            .map(|embeds| embeds.iter().map(embed_len).sum())
+            .unwrap_or(0)
+    }
+
+    /// whether `other` still fits into this message
+    fn fits(&self, other: &Message, max_size: usize, max_embeds: usize) -> bool {
+        // uploads count towards one limit for the whole message
+        self.size() + other.size() <= max_size
+            && self.files.len() + other.files.len() <= MAX_FILES
+            && self.embeds() + other.embeds() <= max_embeds
+            && self.text_len() + other.text_len() <= MAX_EMBED_CHARS
+    }
+
+    fn merge(&mut self, other: Message) {
+        if let (Some(embeds), Value::Array(more)) =
+            (self.body["embeds"].as_array_mut(), other.body["embeds"].clone())
+        {
+            embeds.extend(more);
+        }
+
+        self.files.extend(other.files);
+    }
+
+    fn to_form(&self) -> serde_json::Result<reqwest::multipart::Form> {
+        use reqwest::multipart::{Form, Part};
+
+        let mut payload = self.body.clone();
+        payload["attachments"] = self
+            .files
+            .iter()
+            .enumerate()
+            .map(|(id, f)| serde_json::json!({ "id": id, "filename": f.filename }))
+            .collect();
+
+        let mut form = Form::new().text("payload_json", serde_json::to_string(&payload)?);
+        for (id, f) in self.files.iter().enumerate() {
+            form = form.part(
+                format!("files[{}]", id),
+                Part::bytes(f.data.clone()).file_name(f.filename.clone()),
+            );
+        }
+
+        Ok(form)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WebhookTarget {
+    url: String,
+    username: Option<String>,
+    avatar_url: Option<String>,
+
+    // overrides the global `attachments` for this webhook
+    attachments: Option<bool>,
+
+    // only entries with these ratings go here, e.g. ["explicit"] for an nsfw channel
+    #[serde(default)]
+    ratings: HashSet<Rating>,
+
+    #[serde(flatten)]
+    embed: EmbedTemplate,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum WebhookConfig {
+    Url(String),
+    Full(Box<WebhookTarget>),
+}
+
+impl WebhookConfig {
+    fn into_target(self) -> WebhookTarget {
+        match self {
+            WebhookConfig::Url(url) => WebhookTarget {
+                url,
+                username: None,
+                avatar_url: None,
+                attachments: None,
+                ratings: HashSet::new(),
+                embed: EmbedTemplate::default(),
+            },
+            WebhookConfig::Full(target) => *target,
+        }
+    }
+}
+
+struct WebhookSender {
     webhook: String,
     dest: String,
     override_discord_ratelimit: Option<f64>,
+    bucket: Arc<AsyncBucket>,
+    http: Http,
+    max_attachment_size: usize,
+}
+
+pub struct DiscordWebhookFilter {
+    dest: String,
+    builder: EmbedBuilder,
+    batch_size: usize,
+    batch_window: Duration,
+    attachments: bool,
+    max_attachment_size: usize,
+    accept: Vec<String>,
+    process: Option<Process>,
+    ratings: HashSet<Rating>,
+    uploaded: usize,
+    http: Http,
+    sender: Option<WebhookSender>,
+    queue: Option<mpsc::UnboundedSender<Message>>,
 }
 
 impl fmt::Debug for DiscordWebhookFilter {
@@ -23,77 +187,379 @@ impl fmt::Debug for DiscordWebhookFilter {
 }
 
 impl DiscordWebhookFilter {
-    fn new(dest: String, webhook: String, override_discord_ratelimit: Option<f64>) -> Self {
-        Self {
-            webhook,
+    fn new(
+        dest: String,
+        target: WebhookTarget,
+        config: &DiscordConfig,
+        http: Http,
+    ) -> Result<Self, Box<dyn Error>> {
+        let builder = EmbedBuilder::new(
+            dest.clone(),
+            target.embed.or(&config.embed),
+            &[
+                ("username", &target.username),
+                ("avatar_url", &target.avatar_url),
+            ],
+        )?;
+
+        let sender = WebhookSender {
+            bucket: bucket_for(&target.url),
+            webhook: target.url,
+            dest: dest.clone(),
+            override_discord_ratelimit: config.override_discord_ratelimit,
+            http: http.clone(),
+            max_attachment_size: config.max_attachment_size,
+        };
+
+        Ok(Self {
             dest,
-            override_discord_ratelimit
+            builder,
+            batch_size: config.batch_size.clamp(1, MAX_EMBEDS),
+            batch_window: Duration::from_secs_f64(config.batch_window.max(0.0)),
+            attachments: target.attachments.unwrap_or(config.attachments),
+            max_attachment_size: config.max_attachment_size,
+            accept: config.accept.clone(),
+            process: config.process.clone(),
+            ratings: target.ratings,
+            uploaded: 0,
+            http,
+            sender: Some(sender),
+            queue: None,
+        })
+    }
+
+    /// downloads `url` unless it is bigger than `limit`
+    async fn download(&self, url: &str, limit: usize) -> reqwest::Result<Option<Vec<u8>>> {
+        let mut res = self.http.send(self.http.get(url)).await?.error_for_status()?;
+
+        if res.content_length().unwrap_or(0) as usize > limit {
+            return Ok(None);
         }
+
+        // not every server says how big it is up front, so stop reading as soon as it is too big
+        let mut data = Vec::new();
+        while let Some(chunk) = res.chunk().await? {
+            if data.len() + chunk.len() > limit {
+                return Ok(None);
+            }
+
+            data.extend_from_slice(&chunk);
+        }
+
+        Ok(Some(data))
     }
 
-    async fn send(&self, entry: &EntryBox) -> reqwest::Result<reqwest::Response> {
-        log::debug!("Sending {:?} into {}", entry, self.dest);
+    /// the content of `media` and a name for it, from disk if an earlier output already saved it,
+    /// `None` if it is bigger than `limit`
+    async fn fetch(&self, media: &Media, limit: usize) -> Result<Option<(String, Vec<u8>)>, Box<dyn Error + Send + Sync>> {
+        let url = &media.url;
+        let name = media.filename();
 
-        let mut body = serde_json::json!({
-            "embeds" : [
-                {
-                    "title": entry.title().map(|t| t.into()).unwrap_or(Value::Null),
-                    "color": 10034204u32,
-                    "url": entry.title_url().map(|t| t.into()).unwrap_or(Value::Null),
-                    "image": {
-                        "url": entry.image_url().map(|t| t.into()).unwrap_or(Value::Null),
+        if let Some(file) = &media.local_file {
+            match fs::metadata(&file).await {
+                Ok(meta) if meta.len() > limit as u64 => return Ok(None),
+                Ok(_) => match fs::read(&file).await {
+                    Ok(data) => {
+                        let local = file.file_name().map(|n| n.to_string_lossy().into_owned());
+                        return Ok(Some((local.unwrap_or(name), data)));
                     }
+                    Err(why) => log::debug!("Could not read {:?}, downloading {} instead: {}", file, url, why),
+                },
+                Err(why) => log::debug!("Could not read {:?}, downloading {} instead: {}", file, url, why),
+            }
+        }
+
+        Ok(self.download(url, limit).await?.map(|data| (name, data)))
+    }
+
+    /// runs `data` through the configured command, keeping it as is if there is none or it does not apply
+    async fn process(&self, name: String, data: Vec<u8>) -> Result<(String, Vec<u8>), Box<dyn Error + Send + Sync>> {
+        let process = match &self.process {
+            Some(process) => process,
+            None => return Ok((name, data)),
+        };
+
+        let input = std::env::temp_dir().join(format!("anke-{}-{}-{}", std::process::id(), self.uploaded, name));
+        fs::write(&input, &data).await?;
+
+        let res = process.run(&input, media::sniff(&data)).await;
+        fs::remove_file(&input).await.ok();
+
+        let output = match res? {
+            Some(output) => output,
+            None => return Ok((name, data)),
+        };
+
+        let processed = fs::read(&output).await;
+        fs::remove_file(&output).await.ok();
+
+        let name = match output.extension() {
+            Some(ext) => format!("{}.{}", name.rsplit_once('.').map_or(name.as_str(), |(stem, _)| stem), ext.to_string_lossy()),
+            None => name,
+        };
+
+        Ok((name, processed?))
+    }
+
+    /// adds `media` to the files of `message`, returns the name it got and whether it is a video
+    async fn attach(&mut self, media: &Media, message: &mut Message) -> Option<(String, bool)> {
+        let url = &media.url;
+
+        // discord limits what all files of a message add up to, not each of them
+        let room = self.max_attachment_size.saturating_sub(message.size());
+
+        let (name, data) = match self.fetch(media, room).await {
+            Ok(Some(content)) => content,
+            Ok(None) => {
+                log::debug!("{} is too big to upload, linking it instead", url);
+                return None;
+            }
+            Err(why) => {
+                log::warn!("Failed downloading {} for upload, linking it instead: {}", url, why);
+                return None;
+            }
+        };
+
+        let (name, data) = match self.process(name, data).await {
+            Ok((_, data)) if data.len() > room => {
+                log::debug!("{} is too big to upload after processing, linking it instead", url);
+                return None;
+            }
+            Ok(processed) => processed,
+            Err(why) => {
+                log::warn!("Failed processing {} for upload, linking it instead: {}", url, why);
+                return None;
+            }
+        };
+
+        let media_type = media::sniff(&data);
+        if !self.accept.is_empty() && !media_type.is_some_and(|t| media::accepts(&self.accept, t)) {
+            log::debug!("Not uploading {}, {:?} is not accepted", url, media_type);
+            return None;
+        }
+
+        self.uploaded += 1;
+        let filename = format!("{}_{}", self.uploaded, name);
+
+        // videos cant be shown inside an embed, discord plays them as a plain attachment
+        let video = match media_type {
+            Some(t) => t.starts_with("video/"),
+            None => is_video(&filename),
+        };
+
+        message.files.push(Attachment {
+            filename: filename.clone(),
+            data,
+        });
+
+        Some((filename, video))
+    }
+
+    /// swaps the hotlinked media the embeds of `message` show for uploaded copies,
+    /// leaving the links in place where that does not work out
+    async fn attach_content(&mut self, entry: &EntryBox, message: &mut Message) {
+        let image_mode = self.builder.image_mode();
+        if image_mode == ImageMode::None {
+            return;
+        }
+
+        let media = entry.media();
+        let find = |url: &str| media.iter().find(|m| m.url == url);
+
+        // the main embed shows the image url, in full or as a thumbnail
+        if let Some(item) = entry.image_url().as_deref().and_then(find) {
+            if let Some((filename, video)) = self.attach(item, message).await {
+                let embed = &mut message.body["embeds"][0];
+                if let Some(embed) = embed.as_object_mut() {
+                    embed.remove("image");
+                    embed.remove("thumbnail");
                 }
-            ]
+
+                if !video {
+                    let key = match image_mode {
+                        ImageMode::Thumbnail => "thumbnail",
+                        _ => "image",
+                    };
+                    embed[key] = serde_json::json!({ "url": format!("attachment://{}", filename) });
+                }
+            }
+        }
+
+        // and the image only embeds after it the rest of a gallery, which only full images get
+        let count = message.body["embeds"].as_array().map_or(0, Vec::len);
+        for i in 1..count {
+            let url = message.body["embeds"][i]["image"]["url"].as_str().map(str::to_owned);
+            let item = match url.as_deref().and_then(find) {
+                Some(item) => item,
+                None => continue,
+            };
+
+            if let Some((filename, _)) = self.attach(item, message).await {
+                message.body["embeds"][i]["image"] = serde_json::json!({ "url": format!("attachment://{}", filename) });
+            }
+        }
+    }
+
+    fn build_message(&self, entry: &EntryBox) -> Message {
+        let context = entry.to_json();
+
+        let mut body = serde_json::json!({
+            "embeds": self.builder.build_embeds(entry)
         });
 
+        if let Some(username) = self.builder.render("username", &context) {
+            body["username"] = username;
+        }
+        if let Some(avatar_url) = self.builder.render("avatar_url", &context) {
+            body["avatar_url"] = avatar_url;
+        }
+
+        Message {
+            body,
+            files: Vec::new(),
+        }
+    }
+
+    /// the queue into the background task doing the actual sending, started on first use
+    fn queue(&mut self) -> &mpsc::UnboundedSender<Message> {
+        if self.queue.is_none() {
+            let (tx, rx) = mpsc::unbounded_channel();
+
+            if let Some(sender) = self.sender.take() {
+                let task = tokio::spawn(sender.run(rx, self.batch_size, self.batch_window));
+                SENDERS.lock().unwrap().push(task);
+            }
+
+            self.queue = Some(tx);
+        }
+
+        self.queue.as_ref().unwrap()
+    }
+
+    /// sends whatever is still queued right away, waiting up to `timeout` for it to go out
+    pub async fn flush(timeout: Duration) {
+        SHUTDOWN.0.send(true).ok();
+
+        let senders = std::mem::take(&mut *SENDERS.lock().unwrap());
+        if senders.is_empty() {
+            return;
+        }
+
+        log::info!("Sending what is left in {} discord queue(s)", senders.len());
+        let all = async {
+            for sender in senders {
+                sender.await.ok();
+            }
+        };
+
+        if time::timeout(timeout, all).await.is_err() {
+            log::warn!("Gave up on the discord queues after {:?}, some messages were not sent", timeout);
+        }
+    }
+}
+
+/// the next queued message, `None` once `deadline` passed or the queue is closed and empty,
+/// a shutdown closes the queue so only what is already in it comes out, without any more waiting
+async fn next_message(
+    rx: &mut mpsc::UnboundedReceiver<Message>,
+    shutdown: &mut watch::Receiver<bool>,
+    deadline: Option<time::Instant>,
+) -> Option<Message> {
+    if !*shutdown.borrow() {
+        let recv = async {
+            match deadline {
+                Some(deadline) => time::timeout_at(deadline, rx.recv()).await.ok().flatten(),
+                None => rx.recv().await,
+            }
+        };
+
+        tokio::select! {
+            message = recv => return message,
+            _ = shutdown.changed() => (),
+        }
+    }
 
+    rx.close();
+    rx.recv().await
+}
+
+impl WebhookSender {
+    /// collects messages for up to `window` after the first one arrives
+    /// and sends them as one message of at most `size` embeds
+    async fn run(self, mut rx: mpsc::UnboundedReceiver<Message>, size: usize, window: Duration) {
+        let mut carry: Option<Message> = None;
+        let mut shutdown = SHUTDOWN.1.clone();
+
+        loop {
+            let mut message = match carry.take() {
+                Some(message) => message,
+                None => match next_message(&mut rx, &mut shutdown, None).await {
+                    Some(message) => message,
+                    None => break,
+                },
+            };
+            let deadline = time::Instant::now() + window;
 
-        body["embeds"][0]["fields"] = Value::Array({
-            let mut fields = Vec::new();
-            for (name, body) in entry.build_extra_fields() {
-                fields.push(serde_json::json!(
-                    {
-                        "name": name,
-                        "value": body
+            while message.embeds() < size {
+                match next_message(&mut rx, &mut shutdown, Some(deadline)).await {
+                    Some(next) if !message.fits(&next, self.max_attachment_size, size) => {
+                        carry = Some(next);
+                        break;
                     }
-                ))            
+                    Some(next) => message.merge(next),
+                    None => break,
+                }
             }
-            fields
-        });
-        
+
+            if let Err(e) = self.send(&message).await {
+                log::error!("Error during webhook POST: {:?}", e);
+            }
+        }
+
+        log::debug!("Queue for {} closed", self.dest);
+    }
+
+    async fn send(&self, message: &Message) -> Result<reqwest::Response, Box<dyn Error>> {
+        log::debug!(
+            "Sending {} embed(s) with {} file(s) into {}",
+            message.embeds(),
+            message.files.len(),
+            self.dest
+        );
+
         loop {
             use reqwest::StatusCode;
 
-            let res = reqwest::Client::new()
-                .post(&self.webhook)
-                .json(&body)
-                .send()
-                .await?;
+            self.bucket.take(1).await;
+
+            let req = self.http.post(&self.webhook);
+            let req = if message.files.is_empty() {
+                req.json(&message.body)
+            } else {
+                req.multipart(message.to_form()?)
+            };
+
+            let res = self.http.send(req).await?;
+
+            sync_ratelimit(&self.bucket, &res);
 
             match res.status() {
                 StatusCode::OK | StatusCode::NO_CONTENT => return Ok(res),
                 StatusCode::TOO_MANY_REQUESTS => {
-                    let mut int = 60.0;
+                    let delay = match self.override_discord_ratelimit {
+                        Some(delay) => Some(delay),
+                        // so this is complete bullshit, if you actually do what this recommends
+                        // you have to wait for like 3-10 minutes, just to get ratelimited after 5 requests again;
+                        // for that reason you can override whether or not to listen to discord or just wait a certain amount of seconds
+                        None => res.json::<Value>().await?["retry_after"].as_f64(),
+                    };
 
-                    if let Some(delay) = self.override_discord_ratelimit {
-                        int = delay;
-                    }
-                    else {
-                        let js = &res.json::<Value>().await?["retry_after"];
-                        if let Some(delay) = js.as_f64() {
-                            // so this is complete bullshit, if you actually do what this recommends
-                            // you have to wait for like 3-10 minutes, just to get ratelimited after 5 requests again;
-                            // for that reason you can override whether or not to listen to discord or just wait a certain amount of seconds
-                            int = delay;
-                            
-                        }
-                    }
-                    log::warn!("Hit discord ratelimit, sleeping for {} seconds", int);
-                    time::sleep(Duration::from_secs_f64(int)).await;
+                    let int = http::retry_after(delay, 60.0);
+                    log::warn!("Hit discord ratelimit, sleeping for {:?}", int);
+                    time::sleep(int).await;
                 }
                 _ => {
-                    return res.error_for_status();
+                    return Ok(res.error_for_status()?);
                 }
             }
         }
@@ -105,8 +571,19 @@ impl OutputFilter for DiscordWebhookFilter {
     type Item = EntryBox;
 
     async fn filter(&mut self, entry: EntryBox) -> Option<EntryBox> {
-        if let Err(e) = self.send(&entry).await {
-            log::error!("Error during webhook POST: {:?}", e);
+        if !self.ratings.is_empty() && !self.ratings.contains(&entry.rating()) {
+            return Some(entry);
+        }
+
+        log::debug!("Queueing {:?} for {}", entry, self.dest);
+
+        let mut message = self.build_message(&entry);
+        if self.attachments {
+            self.attach_content(&entry, &mut message).await;
+        }
+
+        if self.queue().send(message).is_err() {
+            log::error!("Queue for {} is gone, dropping message", self.dest);
         }
 
         Some(entry)
@@ -115,8 +592,35 @@ impl OutputFilter for DiscordWebhookFilter {
 
 #[derive(Debug, Deserialize)]
 pub struct DiscordConfig {
-    webhooks: HashMap<String, String>,
+    webhooks: HashMap<String, WebhookConfig>,
     override_discord_ratelimit: Option<f64>,
+
+    // how long to wait (in seconds) for more entries before sending a message
+    #[serde(default = "_produce_2_0")]
+    batch_window: f64,
+
+    // how many embeds to put into one message, at most 10
+    #[serde(default = "_produce_max_embeds")]
+    batch_size: usize,
+
+    // upload the content as a file instead of linking it
+    #[serde(default)]
+    attachments: bool,
+
+    // in bytes, bigger files get linked instead
+    #[serde(default = "_produce_8mb")]
+    max_attachment_size: usize,
+
+    // mime types to upload, e.g. ["image/*", "video/mp4"], everything else gets linked
+    #[serde(default)]
+    accept: Vec<String>,
+
+    // a command to run uploads through first, e.g. to turn webms into mp4s
+    process: Option<Process>,
+
+    // defaults for every webhook, each webhook can override them
+    #[serde(default)]
+    embed: EmbedTemplate,
 }
 
 impl OutputFilterFactory for DiscordWebhookFilter {
@@ -124,14 +628,163 @@ impl OutputFilterFactory for DiscordWebhookFilter {
 
     const NAME: &'static str = "discord";
 
-    fn build_filters(config: Self::Config) -> Vec<Box<dyn OutputFilter<Item = EntryBox>>> {
+    fn build_filters(
+        mut config: Self::Config,
+        state: &State,
+    ) -> Vec<Box<dyn OutputFilter<Item = EntryBox>>> {
         let mut filters: Vec<Box<dyn OutputFilter<Item = EntryBox>>> = Vec::new();
 
-        for (dest, url) in config.webhooks {
-
-            filters.push(Box::new(DiscordWebhookFilter::new(dest, url, config.override_discord_ratelimit)))
+        for (dest, webhook) in std::mem::take(&mut config.webhooks) {
+            match DiscordWebhookFilter::new(dest.clone(), webhook.into_target(), &config, state.http().clone()) {
+                Ok(filter) => filters.push(Box::new(filter)),
+                Err(why) => log::error!("Skipping discord webhook {}: {}", dest, why),
+            }
         }
 
         filters
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(description: &str) -> Message {
+        Message {
+            body: serde_json::json!({ "embeds": [{ "title": "t", "description": description }] }),
+            files: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn batches_stay_within_the_text_limit() {
+        let mut batch = message(&"a".repeat(2999));
+        let next = message(&"b".repeat(2999));
+        assert_eq!(batch.text_len(), 3000);
+        assert!(batch.fits(&next, usize::MAX, MAX_EMBEDS));
+
+        batch.merge(next);
+        assert_eq!(batch.text_len(), 6000);
+        assert!(!batch.fits(&message(""), usize::MAX, MAX_EMBEDS));
+    }
+
+    #[test]
+    fn batches_stay_within_the_embed_limit() {
+        let batch = message("");
+        assert!(batch.fits(&message(""), usize::MAX, 2));
+        assert!(!batch.fits(&message(""), usize::MAX, 1));
+    }
+
+    #[derive(Debug)]
+    struct Gallery(Vec<Media>, usize);
+
+    impl anke_core::Entry for Gallery {
+        fn media(&self) -> Vec<Media> {
+            self.0.clone()
+        }
+
+        fn title_url(&self) -> Option<String> {
+            Some("https://example.com/post".into())
+        }
+
+        // which of them the main embed shows
+        fn image_url(&self) -> Option<String> {
+            self.0.get(self.1).map(|m| m.url.clone())
+        }
+    }
+
+    fn gallery(path: &str, shown: usize) -> EntryBox {
+        let media = (0..3)
+            .map(|i| Media::new(format!("{}/{}/{}.png", mockito::server_url(), path, i)))
+            .collect();
+
+        Box::new(Gallery(media, shown))
+    }
+
+    fn filter(config: &str) -> DiscordWebhookFilter {
+        let mut config: DiscordConfig = toml::from_str(&format!(
+            "attachments = true\n{}\n[webhooks]\ntest = \"http://127.0.0.1:1/webhook\"",
+            config
+        ))
+        .unwrap();
+        let target = config.webhooks.remove("test").unwrap().into_target();
+        let state = State::new(":memory:".into()).unwrap();
+
+        DiscordWebhookFilter::new("test".into(), target, &config, state.http().clone()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn uploads_of_one_entry_stay_within_the_limit() {
+        let mocks: Vec<_> = (0..3)
+            .map(|i| mockito::mock("GET", format!("/gallery-limit/{}.png", i).as_str()).with_body("1234").create())
+            .collect();
+
+        let mut filter = filter("max_attachment_size = 10");
+        let entry = gallery("gallery-limit", 0);
+        let mut message = filter.build_message(&entry);
+        filter.attach_content(&entry, &mut message).await;
+
+        // the third one does not fit anymore and stays a link
+        assert_eq!(message.files.len(), 2);
+        assert_eq!(message.size(), 8);
+        for mock in &mocks[..2] {
+            mock.assert();
+        }
+        assert!(message.body["embeds"][2]["image"]["url"].as_str().unwrap().starts_with("http"));
+    }
+
+    #[tokio::test]
+    async fn full_images_upload_the_whole_gallery() {
+        let mocks: Vec<_> = (0..3)
+            .map(|i| mockito::mock("GET", format!("/full/{}.png", i).as_str()).with_body("1234").create())
+            .collect();
+
+        let mut filter = filter("");
+        let entry = gallery("full", 0);
+        let mut message = filter.build_message(&entry);
+        filter.attach_content(&entry, &mut message).await;
+
+        let names: Vec<_> = message.files.iter().map(|f| f.filename.as_str()).collect();
+        assert_eq!(names, ["1_0.png", "2_1.png", "3_2.png"]);
+        for (i, name) in names.iter().enumerate() {
+            assert_eq!(message.body["embeds"][i]["image"]["url"], format!("attachment://{}", name));
+        }
+        for mock in &mocks {
+            mock.assert();
+        }
+    }
+
+    #[tokio::test]
+    async fn thumbnails_upload_only_what_is_shown() {
+        let shown = mockito::mock("GET", "/thumbnail/1.png").with_body("1234").expect(1).create();
+        let rest = mockito::mock("GET", mockito::Matcher::Regex("^/thumbnail/[02]".into()))
+            .expect(0)
+            .create();
+
+        let mut filter = filter("[embed]\nimage = \"thumbnail\"");
+        let entry = gallery("thumbnail", 1);
+        let mut message = filter.build_message(&entry);
+        filter.attach_content(&entry, &mut message).await;
+
+        assert_eq!(message.files.len(), 1);
+        assert_eq!(message.body["embeds"].as_array().unwrap().len(), 1);
+        assert_eq!(message.body["embeds"][0]["thumbnail"]["url"], "attachment://1_1.png");
+        shown.assert();
+        rest.assert();
+    }
+
+    #[tokio::test]
+    async fn nothing_shown_nothing_uploaded() {
+        let none = mockito::mock("GET", mockito::Matcher::Regex("^/hidden/".into()))
+            .expect(0)
+            .create();
+
+        let mut filter = filter("[embed]\nimage = \"none\"");
+        let entry = gallery("hidden", 0);
+        let mut message = filter.build_message(&entry);
+        filter.attach_content(&entry, &mut message).await;
+
+        assert!(message.files.is_empty());
+        none.assert();
+    }
+}
@@ -1,35 +1,507 @@
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
-use anke_core::{async_trait, EntryBox, OutputFilter, OutputFilterFactory, reqwest};
+use super::media::{self, Process};
+use super::metadata;
+use super::path_template::{self, PathTemplate};
+use super::storage::StorageConfig;
+use anke_core::{
+    async_trait, serde_json,
+    reqwest::{header, StatusCode},
+    storage::StorageResult,
+    tokio::{fs, io::{AsyncReadExt, AsyncWriteExt}},
+    EntryBox, FileLog, Http, LocalEntry, LocalStorage, Media, OutputFilter, OutputFilterFactory, State, Storage,
+};
+use md5::{Digest, Md5};
 use serde::Deserialize;
-use std::error::Error;
+
+// how many records pruning looks at at once
+const PRUNE_PAGE: usize = 100;
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Collision {
+    Overwrite,
+    Skip,
+    #[default]
+    Rename,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Xmp {
+    #[default]
+    None,
+    Sidecar,
+    Embed,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy)]
+pub struct Limits {
+    // the most one source may take up, its oldest files go first
+    max_size_mib: Option<u64>,
+
+    // files older than this get removed
+    max_age_days: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct Retention {
+    #[serde(flatten)]
+    default: Limits,
+
+    // limits for single sources instead of the ones above
+    #[serde(default)]
+    sources: HashMap<String, Limits>,
+}
+
+impl Retention {
+    fn limits_for(&self, source: &str) -> Limits {
+        self.sources.get(source).copied().unwrap_or(self.default)
+    }
+}
 
 #[derive(Debug, Deserialize)]
 pub struct FilesConfig {
-    root: Option<PathBuf>
+    root: Option<PathBuf>,
+
+    // somewhere other than root to put files, see anke.example.toml for the backends
+    storage: Option<StorageConfig>,
+
+    // where downloads wait until they are complete when the storage is not local
+    staging: Option<PathBuf>,
+
+    // where below root files go, see anke.example.toml for the fields
+    path: Option<String>,
+
+    // what to do when a file already exists
+    #[serde(default)]
+    on_collision: Collision,
+
+    // write a .json with everything known about the entry next to each file
+    #[serde(default)]
+    sidecar: bool,
+
+    // tags, title, artist and source as xmp, next to the file or inside of jpegs and pngs
+    #[serde(default)]
+    xmp: Xmp,
+
+    // how much to keep around per source, everything forever if unset
+    #[serde(default)]
+    retention: Retention,
+
+    // mime types to save, e.g. ["image/*", "video/mp4"], everything if empty
+    #[serde(default)]
+    accept: Vec<String>,
+
+    // a command to run each file through before it is saved
+    process: Option<Process>,
 }
 
 #[derive(Debug)]
 pub struct FilesSavingFilter {
-    config: FilesConfig
+    storage: Box<dyn Storage>,
+    staging: PathBuf,
+    path: PathTemplate,
+    on_collision: Collision,
+    sidecar: bool,
+    xmp: Xmp,
+    retention: Retention,
+    accept: Vec<String>,
+    process: Option<Process>,
+    log: FileLog,
+    http: Http,
+}
+
+/// what ended up in the storage
+struct Saved {
+    key: String,
+    size: u64,
+    media_type: Option<&'static str>,
+}
+
+/// `dest` with `suffix` tacked onto its file name, e.g. for the `.part` a download lives in until it is complete
+fn with_suffix(dest: &Path, suffix: &str) -> PathBuf {
+    let mut name = dest.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+
+    dest.with_file_name(name)
+}
+
+/// what files of `entry` are recorded and pruned under
+fn source_of(entry: &EntryBox) -> String {
+    entry.source_name().unwrap_or_else(|| "unknown".into())
+}
+
+/// the storage key for a relative path
+fn key_of(path: &Path) -> String {
+    itertools::join(path.iter().map(|p| p.to_string_lossy()), "/")
+}
+
+async fn md5_of(path: &Path) -> std::io::Result<String> {
+    let mut file = fs::File::open(path).await?;
+    let mut hasher = Md5::new();
+    let mut buf = vec![0u8; 64 * 1024];
+
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
 }
 
 impl FilesSavingFilter {
-    fn new(config: FilesConfig) -> Self {
+    fn new(storage: Box<dyn Storage>, staging: PathBuf, path: PathTemplate, config: FilesConfig, log: FileLog, http: Http) -> Self {
         Self {
-            config
+            storage,
+            staging,
+            path,
+            on_collision: config.on_collision,
+            sidecar: config.sidecar,
+            xmp: config.xmp,
+            retention: config.retention,
+            accept: config.accept,
+            process: config.process,
+            log,
+            http,
         }
     }
 
-    async fn save_to(&self, url: &String, name: &str) -> Result<(), Box<dyn Error>> {
-        let content = reqwest::get(url).await?.bytes().await?;
-        let mut bytes_reader = std::io::Cursor::new(content);
-        let mut dest = std::fs::File::create(self.config.root.clone().unwrap().join(name))?;
+    /// where to actually put a file meant to go to `dest`, `None` if it should not be saved
+    async fn resolve_collision(&self, dest: PathBuf) -> StorageResult<Option<PathBuf>> {
+        if !self.storage.exists(&key_of(&dest)).await? {
+            return Ok(Some(dest));
+        }
+
+        match self.on_collision {
+            Collision::Overwrite => Ok(Some(dest)),
+            Collision::Skip => Ok(None),
+            Collision::Rename => {
+                let stem = dest.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+                let ext = dest.extension().map(|e| format!(".{}", e.to_string_lossy()));
+
+                for n in 1.. {
+                    let candidate = dest.with_file_name(format!("{}_{}{}", stem, n, ext.as_deref().unwrap_or("")));
+                    if !self.storage.exists(&key_of(&candidate)).await? {
+                        return Ok(Some(candidate));
+                    }
+                }
+
+                Ok(None)
+            }
+        }
+    }
+
+    /// where the download for `key` lives until it is complete, right next to it if the storage is local
+    fn part_for(&self, key: &str) -> PathBuf {
+        match self.storage.local_path(key) {
+            Some(path) => with_suffix(&path, ".part"),
+            None => self.staging.join(format!("{}.part", path_template::sanitize(&key.replace('/', "_")))),
+        }
+    }
+
+    /// streams `url` into the `.part` file at `part`, picking up where an earlier attempt
+    /// left off if the server lets us, and checks that it is complete and intact
+    async fn download(&self, url: &String, part: &Path, md5: Option<String>) -> StorageResult<()> {
+        if let Some(parent) = part.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        let have = fs::metadata(part).await.map(|m| m.len()).unwrap_or(0);
+
+        let mut req = self.http.get(url);
+        if have > 0 {
+            debug!("Resuming {} at {} bytes", url, have);
+            req = req.header(header::RANGE, format!("bytes={}-", have));
+        }
+
+        let mut res = self.http.send(req).await?;
+
+        let (mut file, mut written) = match res.status() {
+            StatusCode::PARTIAL_CONTENT => {
+                let file = fs::OpenOptions::new().append(true).open(part).await?;
+                (file, have)
+            }
+            // the .part is already everything there is
+            StatusCode::RANGE_NOT_SATISFIABLE => {
+                fs::remove_file(part).await?;
+                return Err(format!("server refused to resume {}, will start over next time", url).into());
+            }
+            _ => {
+                res = res.error_for_status()?;
+                (fs::File::create(part).await?, 0)
+            }
+        };
 
-        std::io::copy(&mut bytes_reader, &mut dest)?;
+        let expected = res.content_length().map(|len| len + written);
+
+        while let Some(chunk) = res.chunk().await? {
+            file.write_all(&chunk).await?;
+            written += chunk.len() as u64;
+        }
+
+        file.flush().await?;
+        file.sync_all().await?;
+        drop(file);
+
+        if let Some(expected) = expected {
+            if written != expected {
+                return Err(format!("got {} of {} bytes", written, expected).into());
+            }
+        }
+
+        if let Some(md5) = md5 {
+            let actual = md5_of(part).await?;
+
+            if !actual.eq_ignore_ascii_case(&md5) {
+                fs::remove_file(part).await?;
+                return Err(format!("md5 mismatch, expected {} got {}", md5, actual).into());
+            }
+        }
 
         Ok(())
     }
+
+    /// runs `part` through the configured command, swapping it and the extension of `key` for the output
+    async fn process(&self, part: PathBuf, key: String, media_type: Option<&str>) -> StorageResult<(PathBuf, String)> {
+        let output = match &self.process {
+            Some(process) => process.run(&part, media_type).await?,
+            None => None,
+        };
+
+        let output = match output {
+            Some(output) => output,
+            None => return Ok((part, key)),
+        };
+
+        fs::remove_file(&part).await?;
+
+        let key = match (output.extension(), Path::new(&key).extension()) {
+            (Some(new), Some(old)) if new != old => key_of(&Path::new(&key).with_extension(new)),
+            _ => key,
+        };
+
+        // back to a .part so local storage still just renames it into place
+        let part = self.part_for(&key);
+        fs::rename(&output, &part).await?;
+
+        Ok((part, key))
+    }
+
+    /// downloads `url` and only hands it to the storage once it is complete and checks out,
+    /// `None` if processing it gave it a name that is taken and should not be replaced
+    async fn save_to(&self, entry: &EntryBox, url: &String, key: String, md5: Option<String>) -> StorageResult<Option<Saved>> {
+        let part = self.part_for(&key);
+        self.download(url, &part, md5).await?;
+
+        let downloaded = media::sniff_file(&part).await;
+        let (part, processed) = self.process(part, key.clone(), downloaded).await?;
+
+        // a new extension is a new name, which might be taken already
+        let key = if processed == key {
+            key
+        } else {
+            match self.resolve_collision(PathBuf::from(&processed)).await? {
+                Some(dest) => key_of(&dest),
+                None => {
+                    fs::remove_file(&part).await?;
+                    return Ok(None);
+                }
+            }
+        };
+        let media_type = media::sniff_file(&part).await;
+
+        if !self.accept.is_empty() && !media_type.is_some_and(|t| media::accepts(&self.accept, t)) {
+            fs::remove_file(&part).await?;
+            return Err(format!("{} is {}, which is not accepted", url, media_type.unwrap_or("of an unknown type")).into());
+        }
+
+        if self.xmp == Xmp::Embed {
+            if metadata::can_embed(media_type) {
+                let data = fs::read(&part).await?;
+
+                match metadata::embed_xmp(&data, &metadata::xmp_packet(entry)) {
+                    Some(data) => fs::write(&part, data).await?,
+                    None => warn!("Not embedding xmp into {}, it could not be parsed", key),
+                }
+            } else {
+                debug!("Not embedding xmp into {}, only jpeg and png are supported", key);
+            }
+        }
+
+        let size = fs::metadata(&part).await?.len();
+        self.storage
+            .put_file_with_meta(&key, &part, &metadata::object_metadata(entry, url))
+            .await?;
+
+        Ok(Some(Saved { key, size, media_type }))
+    }
+
+    /// writes the sidecars for `dest`, gives back how many bytes they take up
+    async fn write_metadata(&self, entry: &EntryBox, url: &str, dest: &Path) -> StorageResult<u64> {
+        let mut written = 0;
+
+        if self.sidecar {
+            let file = dest.file_name().unwrap_or_default().to_string_lossy();
+            let js = serde_json::to_vec_pretty(&metadata::sidecar(entry, url, &file))?;
+
+            written += js.len() as u64;
+            self.storage.put(&key_of(&with_suffix(dest, ".json")), js).await?;
+        }
+
+        if self.xmp == Xmp::Sidecar {
+            let xmp = metadata::xmp_packet(entry).into_bytes();
+
+            written += xmp.len() as u64;
+            self.storage.put(&key_of(&with_suffix(dest, ".xmp")), xmp).await?;
+        }
+
+        Ok(written)
+    }
+
+    /// removes `key` and whatever metadata got written next to it
+    async fn remove(&self, key: &str) -> StorageResult<()> {
+        self.storage.delete(key).await?;
+
+        if self.sidecar {
+            self.storage.delete(&format!("{}.json", key)).await?;
+        }
+
+        if self.xmp == Xmp::Sidecar {
+            self.storage.delete(&format!("{}.xmp", key)).await?;
+        }
+
+        self.log.forget(key).await?;
+
+        Ok(())
+    }
+
+    /// removes the oldest files of `source` until it is within its limits again,
+    /// never those in `keep`, which were just written
+    async fn prune(&self, source: &str, keep: &[String]) -> usize {
+        let limits = self.retention.limits_for(source);
+        if limits.max_size_mib.is_none() && limits.max_age_days.is_none() {
+            return 0;
+        }
+
+        let mut total = match self.log.usage_of(source).await {
+            Ok(usage) => usage.bytes,
+            Err(why) => {
+                error!("{} while adding up the files of {}", why, source);
+                return 0;
+            }
+        };
+
+        let now = chrono::Utc::now().timestamp().max(0) as u64;
+        let cutoff = limits.max_age_days.map(|days| now.saturating_sub(days.saturating_mul(24 * 60 * 60)));
+        let max_size = limits.max_size_mib.map(|mib| mib.saturating_mul(1024 * 1024));
+
+        let mut pruned = 0;
+        // records that are still there, kept or failed to be removed, the next page starts after them
+        let mut skipped = 0;
+
+        loop {
+            let records = match self.log.oldest(source, PRUNE_PAGE, skipped).await {
+                Ok(records) if records.is_empty() => return pruned,
+                Ok(records) => records,
+                Err(why) => {
+                    error!("{} while looking up the files of {}", why, source);
+                    return pruned;
+                }
+            };
+
+            for record in records {
+                let too_old = cutoff.is_some_and(|cutoff| record.written_at < cutoff);
+                let too_big = max_size.is_some_and(|max| total > max);
+
+                // oldest first, so everything after this is fine too
+                if !too_old && !too_big {
+                    return pruned;
+                }
+
+                if keep.contains(&record.key) {
+                    skipped += 1;
+                    continue;
+                }
+
+                match self.remove(&record.key).await {
+                    Ok(()) => {
+                        debug!("Pruned {} ({} bytes) of {}", record.key, record.size, source);
+                        total = total.saturating_sub(record.size);
+                        pruned += 1;
+                    }
+                    Err(why) => {
+                        error!("{} while pruning {}", why, record.key);
+                        skipped += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn report_usage(log: &FileLog) {
+        let usage = match log.usage().await {
+            Ok(usage) => usage,
+            Err(why) => return error!("{} while adding up what is stored", why),
+        };
+
+        for (source, usage) in usage {
+            info!(
+                "{} files of {} take up {:.1} MiB",
+                usage.files,
+                source,
+                usage.bytes as f64 / (1024.0 * 1024.0)
+            );
+        }
+    }
+
+    /// saves the `index`th of the `count` media of `entry`, `None` if it was not saved
+    async fn save_media(&self, entry: &EntryBox, media: &Media, index: usize, count: usize, md5: Option<String>) -> Option<Saved> {
+        let url = &media.url;
+
+        let dest = match self.resolve_collision(self.path.render(entry, media, index, count)).await {
+            Ok(Some(dest)) => dest,
+            Ok(None) => {
+                debug!("{} already exists, skipping it", url);
+                return None;
+            }
+            Err(why) => {
+                error!("{} while checking where to put {}", why, url);
+                return None;
+            }
+        };
+
+        let saved = match self.save_to(entry, url, key_of(&dest), md5).await {
+            Ok(Some(saved)) => saved,
+            Ok(None) => {
+                debug!("{} already exists once processed, skipping it", url);
+                return None;
+            }
+            Err(why) => {
+                error!("{} while downloading {} => {:?}", why, url, dest);
+                return None;
+            }
+        };
+
+        let sidecars = match self.write_metadata(entry, url, Path::new(&saved.key)).await {
+            Ok(written) => written,
+            Err(why) => {
+                error!("{} while writing metadata for {}", why, saved.key);
+                0
+            }
+        };
+
+        // the sidecars go when the file goes, so they count towards it
+        if let Err(why) = self.log.record(&source_of(entry), &saved.key, saved.size + sidecars).await {
+            error!("{} while recording {}, it will not be pruned", why, saved.key);
+        }
+
+        Some(saved)
+    }
 }
 
 #[async_trait]
@@ -37,15 +509,47 @@ impl OutputFilter for FilesSavingFilter {
     type Item = EntryBox;
 
     async fn filter(&mut self, entry: EntryBox) -> Option<EntryBox> {
-        if let Some(url) = &entry.content_url() {
-            let name = url.split("/").last().unwrap();
+        let mut media = entry.media();
+        if media.is_empty() {
+            return Some(entry);
+        }
 
-            if let Err(why) = self.save_to(url, name).await {
-                error!("{} while downloading {} => {:?}", why, url, self.config.root.clone().unwrap().join(name));
-            }
+        // the md5 a source gives is for its only file
+        let md5 = if media.len() == 1 { entry.md5() } else { None };
+
+        let mut any_local = false;
+        let mut written = Vec::new();
+        let count = media.len();
+        for (index, item) in media.iter_mut().enumerate() {
+            let saved = match self.save_media(&entry, item, index, count, md5.clone()).await {
+                Some(saved) => saved,
+                None => continue,
+            };
+
+            item.media_type = saved.media_type.map(str::to_owned);
+            item.local_file = self.storage.local_path(&saved.key);
+            any_local |= item.local_file.is_some();
+            written.push(saved.key);
+        }
+
+        // only what was just added to can have gone over its limits
+        let pruned = if written.is_empty() {
+            0
+        } else {
+            self.prune(&source_of(&entry), &written).await
+        };
+
+        if pruned > 0 {
+            info!("Pruned {} files to stay within the retention limits", pruned);
+            Self::report_usage(&self.log).await;
         }
 
-        Some(entry)
+        // later outputs can use the files instead of downloading them again
+        if any_local {
+            Some(LocalEntry::wrap(entry, media))
+        } else {
+            Some(entry)
+        }
     }
 }
 
@@ -54,11 +558,317 @@ impl OutputFilterFactory for FilesSavingFilter {
 
     const NAME: &'static str = "files";
 
-    fn build_filters(config: Self::Config) -> Vec<Box<dyn OutputFilter<Item = EntryBox>>> {
-        if config.root.is_some() {
-            vec![Box::new(FilesSavingFilter::new(config))]
-        } else  {
-            vec![]
+    fn build_filters(
+        mut config: Self::Config,
+        state: &State,
+    ) -> Vec<Box<dyn OutputFilter<Item = EntryBox>>> {
+        let storage: Box<dyn Storage> = match (config.storage.take(), &config.root) {
+            (Some(storage), _) => storage.build(state.http()),
+            (None, Some(root)) => Box::new(LocalStorage::new(root)),
+            (None, None) => return vec![],
+        };
+
+        let staging = config
+            .staging
+            .clone()
+            .unwrap_or_else(|| std::env::temp_dir().join("anke"));
+
+        let path = match config.path.as_deref().map(PathTemplate::parse) {
+            Some(Ok(path)) => path,
+            Some(Err(why)) => {
+                error!("Not saving any files, bad path template: {}", why);
+                return vec![];
+            }
+            None => PathTemplate::default(),
+        };
+
+        let log = state.files_for(Self::NAME);
+        let report = log.clone();
+        anke_core::tokio::spawn(async move { Self::report_usage(&report).await });
+
+        vec![Box::new(FilesSavingFilter::new(storage, staging, path, config, log, state.http().clone()))]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anke_core::{Entry, MemoryStorage};
+    use mockito::{mock, Mock};
+    use std::collections::HashSet;
+
+    #[derive(Debug)]
+    struct Post {
+        url: String,
+        tags: HashSet<String>,
+    }
+
+    /// a post whose file is at `path` on the mock server
+    fn post(path: &str) -> EntryBox {
+        Box::new(Post {
+            url: format!("{}{}", mockito::server_url(), path),
+            tags: ["b", "a"].iter().map(|t| t.to_string()).collect(),
+        })
+    }
+
+    impl Entry for Post {
+        fn source_name(&self) -> Option<String> {
+            Some("test".into())
         }
+
+        fn id(&self) -> Option<String> {
+            Some("1".into())
+        }
+
+        fn content_url(&self) -> Option<String> {
+            Some(self.url.clone())
+        }
+
+        fn title_url(&self) -> Option<String> {
+            Some("https://example.com/post/1".into())
+        }
+
+        fn tags(&self) -> Option<&HashSet<String>> {
+            Some(&self.tags)
+        }
+    }
+
+    /// a post whose file at `path` on the mock server is `body`
+    fn served(path: &str, body: &str) -> (Mock, EntryBox) {
+        (mock("GET", path).with_body(body).create(), post(path))
+    }
+
+    struct Setup {
+        filter: FilesSavingFilter,
+        state: State,
+        _staging: tempfile::TempDir,
+    }
+
+    fn setup(storage: impl FnOnce(&State) -> Box<dyn Storage>, config: &str) -> Setup {
+        let state = State::new(":memory:".into()).unwrap();
+        let staging = tempfile::tempdir().unwrap();
+        let config: FilesConfig = toml::from_str(config).unwrap();
+        let path = PathTemplate::parse(config.path.as_deref().unwrap_or("{filename}")).unwrap();
+
+        let filter = FilesSavingFilter::new(
+            storage(&state),
+            staging.path().to_owned(),
+            path,
+            config,
+            state.files_for(FilesSavingFilter::NAME),
+            state.http().clone(),
+        );
+
+        Setup {
+            filter,
+            state,
+            _staging: staging,
+        }
+    }
+
+    /// the filter writing into a fresh memory storage
+    fn in_memory(config: &str) -> (MemoryStorage, Setup) {
+        let storage = MemoryStorage::new();
+        let boxed = storage.clone();
+
+        (storage, setup(move |_| Box::new(boxed), config))
+    }
+
+    #[tokio::test]
+    async fn saves_into_the_storage() {
+        let (download, post) = served("/saves/a.png", "png");
+        let (storage, mut setup) = in_memory("path = \"{source}/{filename}\"\nsidecar = true");
+
+        setup.filter.filter(post).await.unwrap();
+        download.assert();
+
+        assert_eq!(storage.keys(), ["test/a.png", "test/a.png.json"]);
+        assert_eq!(storage.get("test/a.png"), Some(b"png".to_vec()));
+
+        let sidecar = storage.get("test/a.png.json").unwrap();
+        let js: serde_json::Value = serde_json::from_slice(&sidecar).unwrap();
+        assert_eq!(js["provenance"]["file"], "a.png");
+
+        // the sidecar counts towards the file it belongs to
+        let usage = setup.state.files_for(FilesSavingFilter::NAME).usage_of("test").await.unwrap();
+        assert_eq!(usage.files, 1);
+        assert_eq!(usage.bytes, 3 + sidecar.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn prunes_the_oldest_but_never_what_was_just_written() {
+        let big = "x".repeat(1024 * 1024 + 1);
+        let (download, post) = served("/prune-oldest/big.png", &big);
+        let (storage, mut setup) = in_memory("path = \"{source}/{filename}\"\n[retention]\nmax_size_mib = 1");
+
+        let log = setup.state.files_for(FilesSavingFilter::NAME);
+        for key in ["test/old_1.png", "test/old_2.png", "other/old.png"] {
+            storage.put(key, b"old".to_vec()).await.unwrap();
+            log.record(key.split('/').next().unwrap(), key, 3).await.unwrap();
+        }
+
+        setup.filter.filter(post).await.unwrap();
+        download.assert();
+
+        // over the limit on its own, but just saved, only older files make room
+        assert_eq!(storage.keys(), ["other/old.png", "test/big.png"]);
+        assert_eq!(log.usage_of("test").await.unwrap().files, 1);
+        assert_eq!(log.usage_of("other").await.unwrap().files, 1);
+    }
+
+    #[tokio::test]
+    async fn prunes_in_pages() {
+        let (download, post) = served("/prune-pages/new.png", "new");
+        let (storage, mut setup) = in_memory("path = \"{source}/{filename}\"\n[retention]\nmax_size_mib = 1");
+
+        let log = setup.state.files_for(FilesSavingFilter::NAME);
+        let old = PRUNE_PAGE * 2 + 10;
+        for i in 0..old {
+            let key = format!("test/{}.png", i);
+            storage.put(&key, Vec::new()).await.unwrap();
+            log.record("test", &key, 10 * 1024).await.unwrap();
+        }
+
+        setup.filter.filter(post).await.unwrap();
+        download.assert();
+
+        // whatever is left fits, and it is the newest ones
+        let usage = log.usage_of("test").await.unwrap();
+        assert!(usage.bytes <= 1024 * 1024, "{:?}", usage);
+        assert!(storage.keys().contains(&"test/new.png".to_owned()));
+        assert!(storage.keys().contains(&format!("test/{}.png", old - 1)));
+        assert!(!storage.keys().contains(&"test/0.png".to_owned()));
+        assert_eq!(storage.keys().len() as u64, usage.files);
+    }
+
+    /// downloads `path` from the mock server into a `.part` that already holds `have`
+    async fn download(path: &str, have: Option<&str>, md5: Option<&str>) -> (StorageResult<()>, Option<String>) {
+        let setup = setup(|_| Box::new(MemoryStorage::new()), "");
+        let dir = tempfile::tempdir().unwrap();
+        let part = dir.path().join("a.png.part");
+        if let Some(have) = have {
+            std::fs::write(&part, have).unwrap();
+        }
+
+        let url = format!("{}{}", mockito::server_url(), path);
+        let res = setup.filter.download(&url, &part, md5.map(str::to_owned)).await;
+
+        (res, std::fs::read_to_string(&part).ok())
+    }
+
+    #[tokio::test]
+    async fn downloads_resume_where_they_left_off() {
+        let rest = mock("GET", "/resume/a.png")
+            .match_header("range", "bytes=4-")
+            .with_status(206)
+            .with_body("456789")
+            .create();
+
+        let (res, part) = download("/resume/a.png", Some("0123"), Some("781E5E245D69B566979B86E28D23F2C7")).await;
+        res.unwrap();
+        rest.assert();
+        assert_eq!(part.as_deref(), Some("0123456789"));
+    }
+
+    #[tokio::test]
+    async fn downloads_start_over_when_the_range_is_ignored() {
+        let whole = mock("GET", "/ignored-range/a.png")
+            .match_header("range", "bytes=7-")
+            .with_body("0123456789")
+            .create();
+
+        let (res, part) = download("/ignored-range/a.png", Some("garbage"), None).await;
+        res.unwrap();
+        whole.assert();
+        assert_eq!(part.as_deref(), Some("0123456789"));
+    }
+
+    #[tokio::test]
+    async fn complete_parts_are_dropped_when_the_range_is_refused() {
+        let refused = mock("GET", "/refused-range/a.png").with_status(416).create();
+
+        let (res, part) = download("/refused-range/a.png", Some("0123456789"), None).await;
+        assert!(res.is_err());
+        refused.assert();
+        assert_eq!(part, None);
+    }
+
+    #[tokio::test]
+    async fn truncated_downloads_fail() {
+        let short = mock("GET", "/truncated/a.png")
+            .with_header("content-length", "100")
+            .with_body("0123456789")
+            .create();
+
+        let (res, _) = download("/truncated/a.png", None, None).await;
+        assert!(res.is_err());
+        short.assert();
+    }
+
+    #[tokio::test]
+    async fn downloads_with_the_wrong_md5_are_dropped() {
+        let served = mock("GET", "/wrong-md5/a.png").with_body("0123456789").create();
+
+        let (res, part) = download("/wrong-md5/a.png", None, Some("00000000000000000000000000000000")).await;
+        assert!(res.unwrap_err().to_string().contains("md5 mismatch"));
+        served.assert();
+        assert_eq!(part, None);
+    }
+
+    const PROCESS_TO_GIF: &str = "[process]\ncommand = [\"cp\", \"{input}\", \"{output}\"]\nextension = \"gif\"";
+
+    #[tokio::test]
+    async fn processed_names_are_checked_for_collisions() {
+        let (download, post) = served("/processed-rename/c.png", "png");
+        let (storage, mut setup) = in_memory(&format!("path = \"{{source}}/{{filename}}\"\n{}", PROCESS_TO_GIF));
+        storage.put("test/c.gif", b"taken".to_vec()).await.unwrap();
+
+        setup.filter.filter(post).await.unwrap();
+        download.assert();
+
+        assert_eq!(storage.keys(), ["test/c.gif", "test/c_1.gif"]);
+        assert_eq!(storage.get("test/c.gif"), Some(b"taken".to_vec()));
+        assert_eq!(storage.get("test/c_1.gif"), Some(b"png".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn processed_names_that_are_taken_can_be_skipped() {
+        let (download, post) = served("/processed-skip/d.png", "png");
+        let (storage, mut setup) = in_memory(&format!(
+            "path = \"{{source}}/{{filename}}\"\non_collision = \"skip\"\n{}",
+            PROCESS_TO_GIF
+        ));
+        storage.put("test/d.gif", b"taken".to_vec()).await.unwrap();
+
+        setup.filter.filter(post).await.unwrap();
+        download.assert();
+
+        assert_eq!(storage.keys(), ["test/d.gif"]);
+        assert_eq!(storage.get("test/d.gif"), Some(b"taken".to_vec()));
+        assert_eq!(setup.state.files_for(FilesSavingFilter::NAME).usage_of("test").await.unwrap().files, 0);
+    }
+
+    #[tokio::test]
+    async fn s3_storage_gets_the_metadata() {
+        let (download, post) = served("/s3-metadata/b.png", "png");
+        let missing = mock("HEAD", "/bucket/test/b.png").with_status(404).create();
+        let put = mock("PUT", "/bucket/test/b.png")
+            .match_header("x-amz-meta-tags", "a,b")
+            .match_header("x-amz-meta-post-url", "https%3A%2F%2Fexample.com%2Fpost%2F1")
+            .match_body("png")
+            .create();
+
+        let storage: StorageConfig = toml::from_str(&format!(
+            "backend = \"s3\"\nendpoint = \"{}\"\nbucket = \"bucket\"\naccess_key = \"a\"\nsecret_key = \"s\"",
+            mockito::server_url()
+        ))
+        .unwrap();
+        let mut setup = setup(|state| storage.build(state.http()), "path = \"{source}/{filename}\"");
+
+        setup.filter.filter(post).await.unwrap();
+
+        download.assert();
+        missing.assert();
+        put.assert();
     }
 }
@@ -0,0 +1,514 @@
+use anke_core::{
+    async_trait, error, log, reqwest,
+    serde_json::{self, Value},
+    tokio::fs,
+    EntryBox, Error, Http, OutputFilter, OutputFilterFactory, State, TagCategory,
+};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fmt;
+
+const ACCESS_KEY_HEADER: &str = "Hydrus-Client-API-Access-Key";
+
+fn _produce_api_url() -> String {
+    "http://127.0.0.1:45869".into()
+}
+
+fn _produce_my_tags() -> String {
+    "my tags".into()
+}
+
+fn _produce_true() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HydrusMode {
+    // let hydrus download the post itself
+    #[default]
+    AddUrl,
+    // download the content here and hand hydrus the bytes
+    Upload,
+}
+
+/// which hydrus namespace each kind of tag goes into, empty for none
+#[derive(Debug, Deserialize, Clone)]
+pub struct Namespaces {
+    #[serde(default = "Namespaces::_produce_creator")]
+    artist: String,
+
+    #[serde(default = "Namespaces::_produce_character")]
+    characters: String,
+
+    #[serde(default = "Namespaces::_produce_series")]
+    copyright: String,
+
+    #[serde(default = "Namespaces::_produce_meta")]
+    meta: String,
+
+    #[serde(default = "Namespaces::_produce_species")]
+    species: String,
+
+    // general tags and whatever the source did not put into a category
+    #[serde(default)]
+    tags: String,
+}
+
+impl Namespaces {
+    fn _produce_creator() -> String {
+        "creator".into()
+    }
+
+    fn _produce_character() -> String {
+        "character".into()
+    }
+
+    fn _produce_series() -> String {
+        "series".into()
+    }
+
+    fn _produce_meta() -> String {
+        "meta".into()
+    }
+
+    fn _produce_species() -> String {
+        "species".into()
+    }
+
+    fn for_category(&self, category: TagCategory) -> &str {
+        match category {
+            TagCategory::Artist => &self.artist,
+            TagCategory::Character => &self.characters,
+            TagCategory::Copyright => &self.copyright,
+            TagCategory::Meta => &self.meta,
+            TagCategory::Species => &self.species,
+            TagCategory::General => &self.tags,
+        }
+    }
+}
+
+impl Default for Namespaces {
+    fn default() -> Self {
+        Self {
+            artist: Self::_produce_creator(),
+            characters: Self::_produce_character(),
+            copyright: Self::_produce_series(),
+            meta: Self::_produce_meta(),
+            species: Self::_produce_species(),
+            tags: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HydrusConfig {
+    #[serde(default = "_produce_api_url")]
+    api_url: String,
+
+    access_key: String,
+
+    #[serde(default)]
+    mode: HydrusMode,
+
+    // the tag service to add tags to, by name or, if set, by key
+    #[serde(default = "_produce_my_tags")]
+    tag_service: String,
+    tag_service_key: Option<String>,
+
+    // the page imported urls show up in
+    destination_page: Option<String>,
+
+    // booru style some_tag becomes hydrus style some tag
+    #[serde(default = "_produce_true")]
+    underscores_to_spaces: bool,
+
+    #[serde(default)]
+    namespaces: Namespaces,
+}
+
+pub struct HydrusFilter {
+    config: HydrusConfig,
+    http: Http,
+}
+
+impl fmt::Debug for HydrusFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HydrusFilter")
+            .field("api_url", &self.config.api_url)
+            .finish()
+    }
+}
+
+impl HydrusFilter {
+    fn new(config: HydrusConfig, http: Http) -> Self {
+        Self { config, http }
+    }
+
+    fn tag(&self, namespace: &str, tag: &str) -> String {
+        let tag = if self.config.underscores_to_spaces {
+            tag.replace('_', " ")
+        } else {
+            tag.to_owned()
+        };
+
+        if namespace.is_empty() {
+            tag
+        } else {
+            format!("{}:{}", namespace, tag)
+        }
+    }
+
+    /// every tag of `entry` moved into the namespace of its category, or just the
+    /// artist and characters if the source does not categorize its tags
+    fn build_tags(&self, entry: &EntryBox) -> Vec<String> {
+        let ns = &self.config.namespaces;
+        let mut namespaced = HashSet::new();
+        let mut tags = Vec::new();
+
+        if let Some(categories) = entry.tag_categories().filter(|c| !c.is_empty()) {
+            for (category, in_category) in categories {
+                for tag in in_category {
+                    tags.push(self.tag(ns.for_category(*category), tag));
+                    namespaced.insert(tag.clone());
+                }
+            }
+
+            if let Some(general) = entry.tags() {
+                for tag in general.difference(&namespaced) {
+                    tags.push(self.tag(&ns.tags, tag));
+                }
+            }
+
+            tags.sort();
+            tags.dedup();
+            return tags;
+        }
+
+        if let Some(artist) = entry.artist() {
+            tags.push(self.tag(&ns.artist, &artist));
+            namespaced.insert(artist);
+        }
+
+        if let Some(characters) = entry.characters() {
+            for character in characters {
+                tags.push(self.tag(&ns.characters, character));
+                namespaced.insert(character.clone());
+            }
+        }
+
+        if let Some(general) = entry.tags() {
+            for tag in general.difference(&namespaced) {
+                tags.push(self.tag(&ns.tags, tag));
+            }
+        }
+
+        tags.sort();
+        tags
+    }
+
+    /// `{ service: tags }` keyed however the service was configured
+    fn services_to_tags(&self, tags: Vec<String>) -> (&'static str, Value) {
+        match &self.config.tag_service_key {
+            Some(key) => ("keys", serde_json::json!({ key: tags })),
+            None => ("names", serde_json::json!({ &self.config.tag_service: tags })),
+        }
+    }
+
+    async fn call(&self, endpoint: &str, body: &Value) -> reqwest::Result<Value> {
+        let req = self
+            .http
+            .post(format!("{}{}", self.config.api_url.trim_end_matches('/'), endpoint))
+            .header(ACCESS_KEY_HEADER, &self.config.access_key)
+            .json(body);
+
+        self.http.send(req).await?.error_for_status()?.json().await
+    }
+
+    async fn add_url(&self, entry: &EntryBox) -> error::Result<()> {
+        let url = entry
+            .title_url()
+            .or_else(|| entry.content_url())
+            .ok_or_else(|| Error::parse("entry without a url to add"))?;
+
+        let (by, tags) = self.services_to_tags(self.build_tags(entry));
+
+        let mut body = serde_json::json!({ "url": url });
+        body[format!("service_{}_to_additional_tags", by)] = tags;
+        if let Some(page) = &self.config.destination_page {
+            body["destination_page_name"] = page.clone().into();
+        }
+
+        self.call("/add_urls/add_url", &body).await?;
+
+        Ok(())
+    }
+
+    async fn upload(&self, entry: &EntryBox) -> error::Result<()> {
+        let url = entry
+            .content_url()
+            .ok_or_else(|| Error::parse("entry without content to upload"))?;
+
+        // an earlier output might have saved it already
+        let local = entry.media().into_iter().find(|m| m.url == url).and_then(|m| m.local_file);
+        let data = match local {
+            Some(file) => fs::read(&file).await?,
+            None => self.http.send(self.http.get(&url)).await?.error_for_status()?.bytes().await?.to_vec(),
+        };
+
+        let req = self
+            .http
+            .post(format!("{}/add_files/add_file", self.config.api_url.trim_end_matches('/')))
+            .header(ACCESS_KEY_HEADER, &self.config.access_key)
+            .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+            .body(data);
+        let js: Value = self.http.send(req).await?.error_for_status()?.json().await?;
+
+        let hash = js["hash"]
+            .as_str()
+            .ok_or_else(|| Error::Storage(format!("hydrus did not import {}: {}", url, js["note"])))?
+            .to_owned();
+
+        let (by, tags) = self.services_to_tags(self.build_tags(entry));
+        let mut body = serde_json::json!({ "hash": hash });
+        body[format!("service_{}_to_tags", by)] = tags;
+        self.call("/add_tags/add_tags", &body).await?;
+
+        let urls: Vec<String> = entry.title_url().into_iter().chain(Some(url)).collect();
+        self.call(
+            "/add_urls/associate_url",
+            &serde_json::json!({ "hash": hash, "urls_to_add": urls }),
+        )
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl OutputFilter for HydrusFilter {
+    type Item = EntryBox;
+
+    async fn filter(&mut self, entry: EntryBox) -> Option<EntryBox> {
+        let res = match self.config.mode {
+            HydrusMode::AddUrl => self.add_url(&entry).await,
+            HydrusMode::Upload => self.upload(&entry).await,
+        };
+
+        if let Err(e) = res {
+            log::error!("Error while sending {:?} to hydrus: {}", entry, e);
+        }
+
+        Some(entry)
+    }
+}
+
+impl OutputFilterFactory for HydrusFilter {
+    type Config = HydrusConfig;
+
+    const NAME: &'static str = "hydrus";
+
+    fn build_filters(
+        config: Self::Config,
+        state: &State,
+    ) -> Vec<Box<dyn OutputFilter<Item = EntryBox>>> {
+        vec![Box::new(HydrusFilter::new(config, state.http().clone()))]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anke_core::{Entry, Media};
+    use mockito::{mock, Matcher};
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Default)]
+    struct Post {
+        url: String,
+        artist: Option<String>,
+        characters: HashSet<String>,
+        tags: HashSet<String>,
+        categories: HashMap<TagCategory, HashSet<String>>,
+        local_file: Option<PathBuf>,
+    }
+
+    impl Entry for Post {
+        fn content_url(&self) -> Option<String> {
+            Some(self.url.clone())
+        }
+
+        fn title_url(&self) -> Option<String> {
+            Some("https://example.com/post/1".into())
+        }
+
+        fn media(&self) -> Vec<Media> {
+            let mut media = Media::new(&self.url);
+            media.local_file = self.local_file.clone();
+            vec![media]
+        }
+
+        fn artist(&self) -> Option<String> {
+            self.artist.clone()
+        }
+
+        fn characters(&self) -> Option<&HashSet<String>> {
+            Some(&self.characters)
+        }
+
+        fn tags(&self) -> Option<&HashSet<String>> {
+            Some(&self.tags)
+        }
+
+        fn tag_categories(&self) -> Option<&HashMap<TagCategory, HashSet<String>>> {
+            Some(&self.categories)
+        }
+    }
+
+    fn set(tags: &[&str]) -> HashSet<String> {
+        tags.iter().map(|t| t.to_string()).collect()
+    }
+
+    fn hydrus(config: &str) -> HydrusFilter {
+        let config: HydrusConfig = toml::from_str(&format!("access_key = \"KEY\"\n{}", config)).unwrap();
+        HydrusFilter::new(config, Http::new(Default::default()).unwrap())
+    }
+
+    fn uncategorized() -> EntryBox {
+        Box::new(Post {
+            artist: Some("some_artist".into()),
+            characters: set(&["cynthia_(pokemon)"]),
+            tags: set(&["some_artist", "cynthia_(pokemon)", "long_hair"]),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn uncategorized_tags_get_artist_and_characters_namespaced() {
+        assert_eq!(
+            hydrus("").build_tags(&uncategorized()),
+            ["character:cynthia (pokemon)", "creator:some artist", "long hair"]
+        );
+        assert_eq!(
+            hydrus("underscores_to_spaces = false\n[namespaces]\nartist = \"artist\"\ntags = \"general\"")
+                .build_tags(&uncategorized()),
+            ["artist:some_artist", "character:cynthia_(pokemon)", "general:long_hair"]
+        );
+    }
+
+    #[test]
+    fn categorized_tags_go_into_their_namespaces() {
+        let entry: EntryBox = Box::new(Post {
+            artist: Some("ignored_artist".into()),
+            tags: set(&["helltaker", "lucifer_(helltaker)", "highres", "red_eyes", "uncategorized_tag"]),
+            categories: [
+                (TagCategory::Copyright, set(&["helltaker"])),
+                (TagCategory::Character, set(&["lucifer_(helltaker)"])),
+                (TagCategory::Meta, set(&["highres"])),
+                (TagCategory::General, set(&["red_eyes"])),
+            ]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        });
+
+        assert_eq!(
+            hydrus("[namespaces]\nmeta = \"\"").build_tags(&entry),
+            ["character:lucifer (helltaker)", "highres", "red eyes", "series:helltaker", "uncategorized tag"]
+        );
+    }
+
+    #[tokio::test]
+    async fn urls_are_added_with_their_tags() {
+        let add = mock("POST", "/add-url/add_urls/add_url")
+            .match_header("hydrus-client-api-access-key", "KEY")
+            .match_body(Matcher::Json(serde_json::json!({
+                "url": "https://example.com/post/1",
+                "service_names_to_additional_tags": { "my tags": ["character:cynthia (pokemon)", "creator:some artist", "long hair"] },
+                "destination_page_name": "anke",
+            })))
+            .with_body("{}")
+            .create();
+
+        let mut hydrus = hydrus(&format!(
+            "api_url = \"{}/add-url/\"\ndestination_page = \"anke\"",
+            mockito::server_url()
+        ));
+        hydrus.filter(uncategorized()).await;
+
+        add.assert();
+    }
+
+    #[tokio::test]
+    async fn uploads_reuse_saved_files() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, b"saved").unwrap();
+
+        let download = mock("GET", "/upload/a.png").expect(0).create();
+        let add = mock("POST", "/upload/add_files/add_file")
+            .match_header("hydrus-client-api-access-key", "KEY")
+            .match_header("content-type", "application/octet-stream")
+            .match_body("saved")
+            .with_body(r#"{"status":1,"hash":"abc"}"#)
+            .create();
+        let tag = mock("POST", "/upload/add_tags/add_tags")
+            .match_header("hydrus-client-api-access-key", "KEY")
+            .match_body(Matcher::Json(serde_json::json!({
+                "hash": "abc",
+                "service_keys_to_tags": { "6c6f63616c2074616773": ["long hair"] },
+            })))
+            .with_body("{}")
+            .create();
+        let associate = mock("POST", "/upload/add_urls/associate_url")
+            .match_body(Matcher::Json(serde_json::json!({
+                "hash": "abc",
+                "urls_to_add": ["https://example.com/post/1", format!("{}/upload/a.png", mockito::server_url())],
+            })))
+            .with_body("{}")
+            .create();
+
+        let mut hydrus = hydrus(&format!(
+            "api_url = \"{}/upload\"\nmode = \"upload\"\ntag_service_key = \"6c6f63616c2074616773\"",
+            mockito::server_url()
+        ));
+        hydrus
+            .filter(Box::new(Post {
+                url: format!("{}/upload/a.png", mockito::server_url()),
+                tags: set(&["long_hair"]),
+                local_file: Some(file.path().to_owned()),
+                ..Default::default()
+            }))
+            .await;
+
+        download.assert();
+        add.assert();
+        tag.assert();
+        associate.assert();
+    }
+
+    #[tokio::test]
+    async fn uploads_download_what_is_not_saved() {
+        let download = mock("GET", "/download/a.png").with_body("fetched").create();
+        let add = mock("POST", "/download/add_files/add_file")
+            .match_body("fetched")
+            .with_body(r#"{"status":1,"hash":"def"}"#)
+            .create();
+        let rest = mock("POST", Matcher::Regex("^/download/add_(tags|urls)/".into()))
+            .with_body("{}")
+            .expect(2)
+            .create();
+
+        let mut hydrus = hydrus(&format!("api_url = \"{}/download\"\nmode = \"upload\"", mockito::server_url()));
+        hydrus
+            .filter(Box::new(Post {
+                url: format!("{}/download/a.png", mockito::server_url()),
+                ..Default::default()
+            }))
+            .await;
+
+        download.assert();
+        add.assert();
+        rest.assert();
+    }
+}
@@ -0,0 +1,163 @@
+use anke_core::tokio::{fs, io::AsyncReadExt, process::Command, time};
+use serde::Deserialize;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+fn _produce_300() -> u64 {
+    300
+}
+
+/// the mime type of `data` going by its first few bytes
+pub fn sniff(data: &[u8]) -> Option<&'static str> {
+    match data {
+        [0xFF, 0xD8, 0xFF, ..] => Some("image/jpeg"),
+        [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => Some("image/png"),
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some("image/gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        [0x1A, 0x45, 0xDF, 0xA3, ..] => Some("video/webm"),
+        [_, _, _, _, b'f', b't', b'y', b'p', brand @ ..] if brand.starts_with(b"qt") => Some("video/quicktime"),
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => Some("video/mp4"),
+        _ => None,
+    }
+}
+
+pub async fn sniff_file(path: &Path) -> Option<&'static str> {
+    let mut buf = [0u8; 16];
+    let mut file = fs::File::open(path).await.ok()?;
+    let n = file.read(&mut buf).await.ok()?;
+
+    sniff(&buf[..n])
+}
+
+pub fn extension_for(media_type: &str) -> Option<&'static str> {
+    match media_type {
+        "image/jpeg" => Some("jpg"),
+        "image/png" => Some("png"),
+        "image/gif" => Some("gif"),
+        "image/webp" => Some("webp"),
+        "video/webm" => Some("webm"),
+        "video/mp4" => Some("mp4"),
+        "video/quicktime" => Some("mov"),
+        _ => None,
+    }
+}
+
+/// whether `media_type` is one of `patterns`, which may be `image/*` or `*`, an empty list allows everything
+pub fn accepts(patterns: &[String], media_type: &str) -> bool {
+    patterns.is_empty()
+        || patterns.iter().any(|p| match p.strip_suffix("/*") {
+            Some(prefix) => media_type.split('/').next() == Some(prefix),
+            None => p == "*" || p == media_type,
+        })
+}
+
+/// an external command files get run through before they are delivered
+#[derive(Debug, Deserialize, Clone)]
+pub struct Process {
+    // program and arguments, {input} and {output} get replaced by the paths
+    command: Vec<String>,
+
+    // which mime types to run on, everything if empty
+    #[serde(default)]
+    types: Vec<String>,
+
+    // what the output file should end in, the same as the input if unset
+    extension: Option<String>,
+
+    // in seconds, the command is killed after this
+    #[serde(default = "_produce_300")]
+    timeout: u64,
+}
+
+impl Process {
+    /// runs the command on `input`, returns where its output ended up or `None` if it does not apply
+    pub async fn run(
+        &self,
+        input: &Path,
+        media_type: Option<&str>,
+    ) -> Result<Option<PathBuf>, Box<dyn Error + Send + Sync>> {
+        if !self.types.is_empty() && !media_type.is_some_and(|t| accepts(&self.types, t)) {
+            return Ok(None);
+        }
+
+        let (program, args) = self.command.split_first().ok_or("process command is empty")?;
+
+        let ext = self
+            .extension
+            .as_deref()
+            .or_else(|| media_type.and_then(extension_for))
+            .unwrap_or("out");
+
+        let mut name = input.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".processed.{}", ext));
+        let output = input.with_file_name(name);
+
+        let args: Vec<String> = args
+            .iter()
+            .map(|arg| {
+                arg.replace("{input}", &input.to_string_lossy())
+                    .replace("{output}", &output.to_string_lossy())
+            })
+            .collect();
+
+        debug!("Running {} {:?}", program, args);
+
+        let status = time::timeout(
+            Duration::from_secs(self.timeout),
+            Command::new(program).args(&args).kill_on_drop(true).status(),
+        )
+        .await
+        .map_err(|_| format!("{} took longer than {} seconds", program, self.timeout))??;
+
+        if !status.success() {
+            fs::remove_file(&output).await.ok();
+            return Err(format!("{} exited with {}", program, status).into());
+        }
+
+        if fs::metadata(&output).await.is_err() {
+            return Err(format!("{} did not write {:?}", program, output).into());
+        }
+
+        Ok(Some(output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffs_known_types() {
+        assert_eq!(sniff(&[0xFF, 0xD8, 0xFF, 0xE0]), Some("image/jpeg"));
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0"), Some("image/png"));
+        assert_eq!(sniff(b"GIF87a"), Some("image/gif"));
+        assert_eq!(sniff(b"GIF89a"), Some("image/gif"));
+        assert_eq!(sniff(b"RIFF\0\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(sniff(&[0x1A, 0x45, 0xDF, 0xA3, 0x01]), Some("video/webm"));
+        assert_eq!(sniff(b"\0\0\0\x18ftypmp42"), Some("video/mp4"));
+        assert_eq!(sniff(b"\0\0\0\x14ftypqt  "), Some("video/quicktime"));
+    }
+
+    #[test]
+    fn does_not_guess() {
+        assert_eq!(sniff(b""), None);
+        assert_eq!(sniff(&[0xFF, 0xD8]), None);
+        assert_eq!(sniff(b"GIF88a"), None);
+        assert_eq!(sniff(b"RIFF\0\0\0\0WAVEfmt "), None);
+        assert_eq!(sniff(b"<!DOCTYPE html>"), None);
+    }
+
+    #[test]
+    fn accepts_patterns() {
+        let patterns = |p: &[&str]| p.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+
+        assert!(accepts(&[], "video/mp4"));
+        assert!(accepts(&patterns(&["*"]), "video/mp4"));
+        assert!(accepts(&patterns(&["image/*"]), "image/png"));
+        assert!(!accepts(&patterns(&["image/*"]), "video/mp4"));
+        assert!(!accepts(&patterns(&["image/*"]), "imagery/png"));
+        assert!(accepts(&patterns(&["image/png", "video/webm"]), "video/webm"));
+        assert!(!accepts(&patterns(&["image/png"]), "image/jpeg"));
+    }
+}
@@ -0,0 +1,327 @@
+use anke_core::{serde_json::Value, EntryBox};
+
+const JPEG_SOI: [u8; 2] = [0xFF, 0xD8];
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+// what an APP1 segment holding xmp starts with
+const JPEG_XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const PNG_XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp";
+
+/// everything we know about where a file came from, written next to it as json
+pub fn sidecar(entry: &EntryBox, url: &str, file: &str) -> Value {
+    let mut js = entry.to_json();
+
+    js["provenance"] = anke_core::serde_json::json!({
+        "downloaded_from": url,
+        "file": file,
+        "saved_at": chrono::Utc::now().to_rfc3339(),
+        "saved_by": concat!("anke ", env!("CARGO_PKG_VERSION")),
+    });
+
+    js
+}
+
+/// where `entry` came from and what is in it, for storages that keep a few short fields with each file
+pub fn object_metadata(entry: &EntryBox, url: &str) -> Vec<(&'static str, String)> {
+    let mut meta = vec![("source-url", url.to_owned())];
+    if let Some(title_url) = entry.title_url() {
+        meta.push(("post-url", title_url));
+    }
+    if let Some(artist) = entry.artist() {
+        meta.push(("artist", artist));
+    }
+    // last, it is the one that gets cut short when there is too much
+    if let Some(tags) = entry.tags() {
+        let mut tags: Vec<&String> = tags.iter().collect();
+        tags.sort();
+        meta.push(("tags", itertools::join(tags, ",")));
+    }
+
+    meta
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// an xmp packet with the title, artist, tags and source of `entry`
+pub fn xmp_packet(entry: &EntryBox) -> String {
+    let mut fields = String::new();
+
+    if let Some(title) = entry.title() {
+        fields += &format!(
+            "   <dc:title><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:title>\n",
+            escape_xml(&title)
+        );
+    }
+
+    if let Some(artist) = entry.artist() {
+        fields += &format!(
+            "   <dc:creator><rdf:Seq><rdf:li>{}</rdf:li></rdf:Seq></dc:creator>\n",
+            escape_xml(&artist)
+        );
+    }
+
+    if let Some(tags) = entry.tags() {
+        let mut tags: Vec<&String> = tags.iter().collect();
+        tags.sort();
+
+        fields += "   <dc:subject><rdf:Bag>";
+        for tag in tags {
+            fields += &format!("<rdf:li>{}</rdf:li>", escape_xml(tag));
+        }
+        fields += "</rdf:Bag></dc:subject>\n";
+    }
+
+    if let Some(source) = entry.title_url().or_else(|| entry.source_url()) {
+        fields += &format!("   <dc:source>{}</dc:source>\n", escape_xml(&source));
+    }
+
+    format!(
+        concat!(
+            "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n",
+            "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n",
+            " <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n",
+            "  <rdf:Description rdf:about=\"\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n",
+            "{}",
+            "  </rdf:Description>\n",
+            " </rdf:RDF>\n",
+            "</x:xmpmeta>\n",
+            "<?xpacket end=\"w\"?>"
+        ),
+        fields
+    )
+}
+
+/// puts `packet` into an APP1 segment, replacing any xmp that was already there
+fn embed_jpeg(data: &[u8], packet: &str) -> Option<Vec<u8>> {
+    let len = 2 + JPEG_XMP_HEADER.len() + packet.len();
+    if len > u16::MAX as usize {
+        return None;
+    }
+
+    let mut segment = vec![0xFF, 0xE1];
+    segment.extend_from_slice(&(len as u16).to_be_bytes());
+    segment.extend_from_slice(JPEG_XMP_HEADER);
+    segment.extend_from_slice(packet.as_bytes());
+
+    let mut out = Vec::with_capacity(data.len() + segment.len());
+    out.extend_from_slice(&JPEG_SOI);
+
+    let mut pos = 2;
+    let mut inserted = false;
+
+    // walk the header segments up to the start of the image data
+    while pos + 4 <= data.len() && data[pos] == 0xFF {
+        let marker = data[pos + 1];
+        if marker == 0xDA {
+            break;
+        }
+
+        // the length counts itself, anything shorter is garbage
+        let seg_len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        if seg_len < 2 {
+            return None;
+        }
+
+        let end = pos + 2 + seg_len;
+        if end > data.len() {
+            return None;
+        }
+
+        let is_xmp = marker == 0xE1 && data[pos + 4..end].starts_with(JPEG_XMP_HEADER);
+
+        // keep JFIF/EXIF first, readers expect them there
+        if !inserted && marker != 0xE0 && (marker != 0xE1 || is_xmp) {
+            out.extend_from_slice(&segment);
+            inserted = true;
+        }
+
+        if !is_xmp {
+            out.extend_from_slice(&data[pos..end]);
+        }
+
+        pos = end;
+    }
+
+    if !inserted {
+        out.extend_from_slice(&segment);
+    }
+
+    out.extend_from_slice(&data[pos..]);
+
+    Some(out)
+}
+
+fn png_chunk(kind: &[u8], body: &[u8]) -> Vec<u8> {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(body);
+
+    let mut chunk = Vec::with_capacity(body.len() + 12);
+    chunk.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(body);
+    chunk.extend_from_slice(&crc.finalize().to_be_bytes());
+
+    chunk
+}
+
+/// puts `packet` into an iTXt chunk right after IHDR, replacing any xmp that was already there
+fn embed_png(data: &[u8], packet: &str) -> Option<Vec<u8>> {
+    // keyword, no compression, no language, no translated keyword
+    let mut body = PNG_XMP_KEYWORD.to_vec();
+    body.extend_from_slice(&[0, 0, 0, 0, 0]);
+    body.extend_from_slice(packet.as_bytes());
+    let xmp = png_chunk(b"iTXt", &body);
+
+    let mut out = Vec::with_capacity(data.len() + xmp.len());
+    out.extend_from_slice(&PNG_SIGNATURE);
+
+    let mut pos = PNG_SIGNATURE.len();
+    while pos + 8 <= data.len() {
+        let len = u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        let kind = &data[pos + 4..pos + 8];
+        let end = pos + 12 + len;
+        if end > data.len() {
+            return None;
+        }
+
+        let is_xmp = kind == b"iTXt" && data[pos + 8..end - 4].starts_with(PNG_XMP_KEYWORD);
+        if !is_xmp {
+            out.extend_from_slice(&data[pos..end]);
+        }
+
+        if kind == b"IHDR" {
+            out.extend_from_slice(&xmp);
+        }
+
+        pos = end;
+    }
+
+    Some(out)
+}
+
+/// whether xmp can be embedded into files of `media_type` at all
+pub fn can_embed(media_type: Option<&str>) -> bool {
+    matches!(media_type, Some("image/jpeg" | "image/png"))
+}
+
+/// `data` with `packet` embedded, `None` for anything that is not a jpeg or png
+pub fn embed_xmp(data: &[u8], packet: &str) -> Option<Vec<u8>> {
+    if data.starts_with(&JPEG_SOI) {
+        embed_jpeg(data, packet)
+    } else if data.starts_with(&PNG_SIGNATURE) {
+        embed_png(data, packet)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png() -> Vec<u8> {
+        let mut data = PNG_SIGNATURE.to_vec();
+        data.extend(png_chunk(b"IHDR", &[0, 0, 0, 1, 0, 0, 0, 1, 8, 0, 0, 0, 0]));
+        data.extend(png_chunk(b"IDAT", &[1, 2, 3]));
+        data.extend(png_chunk(b"IEND", &[]));
+        data
+    }
+
+    /// the kinds of all chunks in `data`, in order
+    fn chunks(data: &[u8]) -> Vec<String> {
+        let mut kinds = Vec::new();
+        let mut pos = PNG_SIGNATURE.len();
+        while pos + 8 <= data.len() {
+            let len = u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+            kinds.push(String::from_utf8_lossy(&data[pos + 4..pos + 8]).into_owned());
+            pos += 12 + len;
+        }
+        assert_eq!(pos, data.len());
+        kinds
+    }
+
+    fn jpeg(segments: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut data = JPEG_SOI.to_vec();
+        for (marker, body) in segments {
+            data.extend_from_slice(&[0xFF, *marker]);
+            data.extend_from_slice(&(body.len() as u16 + 2).to_be_bytes());
+            data.extend_from_slice(body);
+        }
+        data.extend_from_slice(&[0xFF, 0xDA, 0, 2, 0xAB, 0xFF, 0xD9]);
+        data
+    }
+
+    #[test]
+    fn jpeg_gets_xmp_after_jfif_and_exif() {
+        let data = jpeg(&[(0xE0, b"JFIF\0"), (0xE1, b"Exif\0\0"), (0xDB, &[0; 4])]);
+        let out = embed_xmp(&data, "<x/>").unwrap();
+
+        let mut app1 = vec![0xFF, 0xE1];
+        app1.extend_from_slice(&((2 + JPEG_XMP_HEADER.len() + 4) as u16).to_be_bytes());
+        app1.extend_from_slice(JPEG_XMP_HEADER);
+        app1.extend_from_slice(b"<x/>");
+
+        let exif_end = 2 + 9 + 10;
+        assert_eq!(&out[..exif_end], &data[..exif_end]);
+        assert_eq!(&out[exif_end..exif_end + app1.len()], &app1[..]);
+        assert_eq!(&out[exif_end + app1.len()..], &data[exif_end..]);
+
+        // embedding again replaces the packet instead of adding one
+        let again = embed_xmp(&out, "<y/>").unwrap();
+        assert_eq!(again.len(), out.len());
+        assert_eq!(again, embed_xmp(&data, "<y/>").unwrap());
+    }
+
+    #[test]
+    fn malformed_jpeg_is_refused() {
+        for len in [0u8, 1] {
+            let mut data = JPEG_SOI.to_vec();
+            data.extend_from_slice(&[0xFF, 0xE1, 0, len, 0, 0, 0, 0]);
+            assert_eq!(embed_xmp(&data, "<x/>"), None, "length {}", len);
+        }
+
+        let data = jpeg(&[(0xE0, b"JFIF\0")]);
+        assert_eq!(embed_xmp(&data[..8], "<x/>"), None);
+    }
+
+    #[test]
+    fn only_jpeg_and_png_get_xmp() {
+        assert!(can_embed(Some("image/jpeg")));
+        assert!(can_embed(Some("image/png")));
+        assert!(!can_embed(Some("image/gif")));
+        assert!(!can_embed(None));
+        assert_eq!(embed_xmp(b"GIF89a", "<x/>"), None);
+    }
+
+    #[test]
+    fn png_gets_xmp_after_ihdr() {
+        let out = embed_xmp(&png(), "<x/>").unwrap();
+
+        assert_eq!(chunks(&out), ["IHDR", "iTXt", "IDAT", "IEND"]);
+        let body = b"XML:com.adobe.xmp\0\0\0\0\0<x/>";
+        assert!(out.windows(body.len()).any(|w| w == body));
+        assert!(out.ends_with(&png_chunk(b"IEND", &[])));
+    }
+
+    #[test]
+    fn png_xmp_is_replaced() {
+        let once = embed_png(&png(), "<old/>").unwrap();
+        let twice = embed_png(&once, "<new/>").unwrap();
+
+        assert_eq!(chunks(&twice), ["IHDR", "iTXt", "IDAT", "IEND"]);
+        assert_eq!(twice, embed_png(&png(), "<new/>").unwrap());
+    }
+
+    #[test]
+    fn truncated_png_is_refused() {
+        let data = png();
+        assert_eq!(embed_png(&data[..data.len() - 3], "<x/>"), None);
+    }
+}
@@ -1,14 +1,33 @@
+mod discord;
+
 pub mod discord_webhook;
 pub use discord_webhook::DiscordWebhookFilter;
 
+pub mod discord_bot;
+pub use discord_bot::DiscordBotFilter;
+
 pub mod warning_filter;
 pub use warning_filter::WarningFilter;
 
 pub mod blacklist;
 pub use blacklist::BlacklistFilter;
 
+mod media;
+mod metadata;
+mod path_template;
+mod storage;
+
 pub mod files;
 pub use files::FilesSavingFilter;
 
 pub mod dedupe;
 pub use dedupe::DedupeFilter;
+
+pub mod webhook;
+pub use webhook::WebhookFilter;
+
+pub mod hydrus;
+pub use hydrus::HydrusFilter;
+
+pub mod s3;
+pub use s3::S3Filter;
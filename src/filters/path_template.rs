@@ -0,0 +1,275 @@
+use anke_core::{EntryBox, Media, TagCategory};
+use std::path::{Component, Path, PathBuf};
+
+// what gets put in for fields the entry does not have
+const MISSING: &str = "unknown";
+
+// most filesystems choke somewhere above 255 bytes per name
+const MAX_PART_LEN: usize = 120;
+
+// names windows will not create files under, whatever the extension
+const RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+const FIELDS: &[&str] = &[
+    "source", "tag", "artist", "copyright", "id", "md5", "title", "index", "name", "ext", "filename",
+];
+
+#[derive(Debug, Clone)]
+enum Part {
+    Literal(String),
+    Field(String),
+}
+
+/// a relative path like `{source}/{tag}/{id}_{md5}.{ext}` filled in from an entry
+#[derive(Debug, Clone)]
+pub struct PathTemplate {
+    parts: Vec<Part>,
+}
+
+/// makes `s` safe to use as (part of) a single file or directory name
+pub fn sanitize(s: &str) -> String {
+    let cleaned: String = s
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    let cleaned = cleaned.trim_matches(|c: char| c == '.' || c.is_whitespace());
+
+    let mut end = cleaned.len().min(MAX_PART_LEN);
+    while !cleaned.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    let cleaned = &cleaned[..end];
+    let stem = cleaned.split('.').next().unwrap_or_default().trim_end();
+
+    if cleaned.is_empty() {
+        "_".into()
+    } else if RESERVED.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        format!("_{}", cleaned)
+    } else {
+        cleaned.to_owned()
+    }
+}
+
+impl PathTemplate {
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_owned()));
+            }
+
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed {{ in {:?}", template))?;
+            let field = rest[start + 1..start + end].trim();
+
+            if !FIELDS.contains(&field) {
+                return Err(format!(
+                    "unknown field {{{}}} in {:?}, known are {:?}",
+                    field, template, FIELDS
+                ));
+            }
+
+            parts.push(Part::Field(field.to_owned()));
+            rest = &rest[start + end + 1..];
+        }
+
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_owned()));
+        }
+
+        Ok(Self { parts })
+    }
+
+    fn field(entry: &EntryBox, media: &Media, index: usize, field: &str) -> Option<String> {
+        let filename = media.filename();
+        let filename = filename.as_str();
+        let (name, ext) = match filename.rfind('.') {
+            Some(dot) => (&filename[..dot], Some(&filename[dot + 1..])),
+            None => (filename, None),
+        };
+
+        match field {
+            "source" => entry.source_name(),
+            "tag" => entry.source_tag(),
+            "artist" => entry.artist(),
+            // the first one, so the same post always ends up in the same place
+            "copyright" => entry
+                .tags_in(TagCategory::Copyright)
+                .and_then(|tags| tags.iter().min().cloned()),
+            "id" => entry.id(),
+            "md5" => entry.md5(),
+            "title" => entry.title(),
+            // counting from 1, for posts with several files
+            "index" => Some((index + 1).to_string()),
+            "name" => Some(name.to_owned()),
+            "ext" => ext.map(str::to_owned),
+            "filename" => Some(filename.to_owned()),
+            _ => None,
+        }
+        .filter(|s| !s.is_empty())
+    }
+
+    fn has_field(&self, field: &str) -> bool {
+        self.parts.iter().any(|part| matches!(part, Part::Field(f) if f == field))
+    }
+
+    /// the path for the `index`th of the `count` media of `entry`, always relative and inside of wherever it gets joined to;
+    /// if there are several and the template does not tell them apart, `_{index}` is added to the file name
+    pub fn render(&self, entry: &EntryBox, media: &Media, index: usize, count: usize) -> PathBuf {
+        let rendered: String = self
+            .parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(s) => s.clone(),
+                Part::Field(f) => sanitize(&Self::field(entry, media, index, f).unwrap_or_else(|| MISSING.into())),
+            })
+            .collect();
+
+        let mut path: PathBuf = Path::new(&rendered)
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(part) => Some(sanitize(&part.to_string_lossy())),
+                _ => None,
+            })
+            .collect();
+
+        // nothing left would be wherever it gets joined to itself
+        if path.as_os_str().is_empty() {
+            path = PathBuf::from(MISSING);
+        }
+
+        if count > 1 && !self.has_field("index") {
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+            let name = match path.extension() {
+                Some(ext) => format!("{}_{}.{}", stem, index + 1, ext.to_string_lossy()),
+                None => format!("{}_{}", stem, index + 1),
+            };
+            path.set_file_name(name);
+        }
+
+        path
+    }
+}
+
+impl Default for PathTemplate {
+    fn default() -> Self {
+        Self::parse("{filename}").unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anke_core::Entry;
+
+    #[derive(Debug, Default)]
+    struct Post {
+        tag: Option<String>,
+        id: Option<String>,
+    }
+
+    impl Entry for Post {
+        fn source_name(&self) -> Option<String> {
+            Some("gelbooru".into())
+        }
+
+        fn source_tag(&self) -> Option<String> {
+            self.tag.clone()
+        }
+
+        fn id(&self) -> Option<String> {
+            self.id.clone()
+        }
+    }
+
+    fn render(template: &str, post: Post) -> PathBuf {
+        let entry: EntryBox = Box::new(post);
+        let media = Media::new("https://example.com/images/ab/cd/abcd.png?1234");
+
+        PathTemplate::parse(template).unwrap().render(&entry, &media, 0, 1)
+    }
+
+    #[test]
+    fn parses_known_fields_only() {
+        assert!(PathTemplate::parse("{source}/{ tag }/{id}_{index}.{ext}").is_ok());
+        assert!(PathTemplate::parse("no fields at all").is_ok());
+        assert!(PathTemplate::parse("{source}/{nope}").is_err());
+        assert!(PathTemplate::parse("{source}/{id").is_err());
+    }
+
+    #[test]
+    fn renders_fields() {
+        let post = Post {
+            tag: Some("cat ears".into()),
+            id: Some("42".into()),
+        };
+
+        assert_eq!(
+            render("{source}/{tag}/{id}_{index}.{ext}", post),
+            Path::new("gelbooru/cat ears/42_1.png")
+        );
+        assert_eq!(render("{name}-{filename}", Post::default()), Path::new("abcd-abcd.png"));
+        assert_eq!(render("{tag}/{id}", Post::default()), Path::new("unknown/unknown"));
+    }
+
+    #[test]
+    fn stays_inside_the_root() {
+        let post = Post {
+            tag: Some("../../etc".into()),
+            id: Some("/passwd".into()),
+        };
+        assert_eq!(render("{tag}/{id}", post), Path::new("_.._etc/_passwd"));
+
+        assert_eq!(render("/../{source}/./../x", Post::default()), Path::new("gelbooru/x"));
+        assert_eq!(render("..", Post::default()), Path::new(MISSING));
+    }
+
+    #[test]
+    fn galleries_do_not_collide() {
+        let entry: EntryBox = Box::<Post>::default();
+        let media = Media::new("https://example.com/a.png");
+
+        let template = PathTemplate::parse("{source}/{name}.{ext}").unwrap();
+        assert_eq!(template.render(&entry, &media, 0, 1), Path::new("gelbooru/a.png"));
+        assert_eq!(template.render(&entry, &media, 0, 3), Path::new("gelbooru/a_1.png"));
+        assert_eq!(template.render(&entry, &media, 2, 3), Path::new("gelbooru/a_3.png"));
+
+        let template = PathTemplate::parse("{source}/{id}").unwrap();
+        assert_eq!(template.render(&entry, &media, 1, 2), Path::new("gelbooru/unknown_2"));
+
+        // the template already tells them apart
+        let template = PathTemplate::parse("{index}/{name}.{ext}").unwrap();
+        assert_eq!(template.render(&entry, &media, 1, 2), Path::new("2/a.png"));
+    }
+
+    #[test]
+    fn sanitizes_names() {
+        assert_eq!(sanitize(r#"a/b\c:d*e?f"g<h>i|j"#), "a_b_c_d_e_f_g_h_i_j");
+        assert_eq!(sanitize("tab\there"), "tab_here");
+        assert_eq!(sanitize(" ..hidden.. "), "hidden");
+        assert_eq!(sanitize(".."), "_");
+        assert_eq!(sanitize(""), "_");
+        assert_eq!(sanitize(&"\u{e9}".repeat(100)).len(), MAX_PART_LEN);
+    }
+
+    #[test]
+    fn avoids_reserved_names() {
+        assert_eq!(sanitize("con"), "_con");
+        assert_eq!(sanitize("NUL.txt"), "_NUL.txt");
+        assert_eq!(sanitize("lpt9 .png"), "_lpt9 .png");
+        assert_eq!(sanitize("console"), "console");
+        assert_eq!(sanitize("com10"), "com10");
+    }
+}
@@ -0,0 +1,21 @@
+use super::s3::{S3Client, S3Config};
+use anke_core::{Http, LocalStorage, Storage};
+use serde::Deserialize;
+use std::path::PathBuf;
+
+/// which backend a sink writes to, the `backend` key picks one
+#[derive(Debug, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum StorageConfig {
+    Local { root: PathBuf },
+    S3(S3Config),
+}
+
+impl StorageConfig {
+    pub fn build(self, http: &Http) -> Box<dyn Storage> {
+        match self {
+            StorageConfig::Local { root } => Box::new(LocalStorage::new(root)),
+            StorageConfig::S3(config) => Box::new(S3Client::new(config, http.clone())),
+        }
+    }
+}
@@ -0,0 +1,273 @@
+use anke_core::{
+    async_trait, http, log, reqwest,
+    reqwest::{Method, StatusCode},
+    tokio::time,
+    EntryBox, Http, OutputFilter, OutputFilterFactory, Rating, State,
+};
+use handlebars::Handlebars;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+fn _produce_post() -> String {
+    "POST".into()
+}
+
+fn _produce_retry_on() -> Vec<u16> {
+    vec![429, 500, 502, 503, 504]
+}
+
+fn _produce_3() -> usize {
+    3
+}
+
+fn _produce_5_0() -> f64 {
+    5.0
+}
+
+fn _produce_true() -> bool {
+    true
+}
+
+/// how long to wait before retrying, a `Retry-After` that is not a sane number of seconds is ignored
+fn retry_delay(configured: f64, retry_after: Option<&str>) -> Duration {
+    http::retry_after(retry_after.and_then(|h| h.trim().parse::<f64>().ok()), configured)
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Escape {
+    #[default]
+    None,
+    Json,
+    Html,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WebhookTarget {
+    url: String,
+
+    #[serde(default = "_produce_post")]
+    method: String,
+
+    #[serde(default)]
+    headers: HashMap<String, String>,
+
+    body: Option<String>,
+
+    // how values get escaped when rendered into the templates,
+    // use "json" when the body is a json document
+    #[serde(default)]
+    escape: Escape,
+
+    // status codes that count as delivered, any 2xx if not set
+    success: Option<Vec<u16>>,
+
+    // status codes that get retried after waiting
+    #[serde(default = "_produce_retry_on")]
+    retry_on: Vec<u16>,
+
+    #[serde(default = "_produce_3")]
+    max_retries: usize,
+
+    // in seconds
+    #[serde(default = "_produce_5_0")]
+    retry_delay: f64,
+
+    // whether a numeric Retry-After header overrides retry_delay
+    #[serde(default = "_produce_true")]
+    respect_retry_after: bool,
+
+    // only entries with these ratings get sent, all if empty
+    #[serde(default)]
+    ratings: HashSet<Rating>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WebhookConfig {
+    #[serde(flatten)]
+    targets: HashMap<String, WebhookTarget>,
+}
+
+pub struct WebhookFilter {
+    dest: String,
+    target: WebhookTarget,
+    method: Method,
+    templates: Handlebars<'static>,
+    http: Http,
+}
+
+impl fmt::Debug for WebhookFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WebhookFilter")
+            .field("dest", &self.dest)
+            .finish()
+    }
+}
+
+impl WebhookFilter {
+    fn new(dest: String, target: WebhookTarget, http: Http) -> Result<Self, Box<dyn Error>> {
+        let method = Method::from_bytes(target.method.to_uppercase().as_bytes())?;
+
+        let mut templates = Handlebars::new();
+        match target.escape {
+            Escape::None => templates.register_escape_fn(handlebars::no_escape),
+            Escape::Html => templates.register_escape_fn(handlebars::html_escape),
+            Escape::Json => templates.register_escape_fn(|s| {
+                let quoted = anke_core::serde_json::to_string(s).unwrap_or_default();
+                quoted[1..quoted.len() - 1].to_owned()
+            }),
+        }
+
+        templates.register_template_string("url", &target.url)?;
+        if let Some(body) = &target.body {
+            templates.register_template_string("body", body)?;
+        }
+        for (name, value) in &target.headers {
+            templates.register_template_string(&format!("header:{}", name), value)?;
+        }
+
+        Ok(Self {
+            dest,
+            target,
+            method,
+            templates,
+            http,
+        })
+    }
+
+    fn is_success(&self, status: StatusCode) -> bool {
+        match &self.target.success {
+            Some(codes) => codes.contains(&status.as_u16()),
+            None => status.is_success(),
+        }
+    }
+
+    async fn send(&self, entry: &EntryBox) -> Result<reqwest::Response, Box<dyn Error>> {
+        log::debug!("Sending {:?} into {}", entry, self.dest);
+
+        let context = entry.to_json();
+
+        let url = self.templates.render("url", &context)?;
+        let body = match self.target.body {
+            Some(_) => Some(self.templates.render("body", &context)?),
+            None => None,
+        };
+        let mut headers = Vec::with_capacity(self.target.headers.len());
+        for name in self.target.headers.keys() {
+            headers.push((
+                name.clone(),
+                self.templates.render(&format!("header:{}", name), &context)?,
+            ));
+        }
+
+        let mut retries = 0;
+
+        loop {
+            let mut req = self.http.request(self.method.clone(), &url);
+            for (name, value) in &headers {
+                req = req.header(name, value);
+            }
+            if let Some(body) = &body {
+                req = req.body(body.clone());
+            }
+
+            let res = self.http.send(req).await?;
+            let status = res.status();
+
+            if self.is_success(status) {
+                return Ok(res);
+            }
+
+            if !self.target.retry_on.contains(&status.as_u16()) || retries >= self.target.max_retries
+            {
+                return Err(format!("{} answered with {}", self.dest, status).into());
+            }
+
+            let retry_after = res
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|h| h.to_str().ok())
+                .filter(|_| self.target.respect_retry_after);
+            let int = retry_delay(self.target.retry_delay, retry_after);
+
+            retries += 1;
+            log::warn!(
+                "{} answered with {}, retrying ({}/{}) in {:?}",
+                self.dest,
+                status,
+                retries,
+                self.target.max_retries,
+                int
+            );
+            time::sleep(int).await;
+        }
+    }
+}
+
+#[async_trait]
+impl OutputFilter for WebhookFilter {
+    type Item = EntryBox;
+
+    async fn filter(&mut self, entry: EntryBox) -> Option<EntryBox> {
+        if !self.target.ratings.is_empty() && !self.target.ratings.contains(&entry.rating()) {
+            return Some(entry);
+        }
+
+        if let Err(e) = self.send(&entry).await {
+            log::error!("Error during webhook request to {}: {:?}", self.dest, e);
+        }
+
+        Some(entry)
+    }
+}
+
+impl OutputFilterFactory for WebhookFilter {
+    type Config = WebhookConfig;
+
+    const NAME: &'static str = "webhook";
+
+    fn build_filters(
+        config: Self::Config,
+        state: &State,
+    ) -> Vec<Box<dyn OutputFilter<Item = EntryBox>>> {
+        let mut filters: Vec<Box<dyn OutputFilter<Item = EntryBox>>> = Vec::new();
+
+        for (dest, target) in config.targets {
+            match WebhookFilter::new(dest.clone(), target, state.http().clone()) {
+                Ok(filter) => filters.push(Box::new(filter)),
+                Err(why) => log::error!("Skipping webhook {}: {}", dest, why),
+            }
+        }
+
+        filters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_after_wins_when_sane() {
+        assert_eq!(retry_delay(5.0, Some("2")), Duration::from_secs(2));
+        assert_eq!(retry_delay(5.0, Some(" 1.5 ")), Duration::from_secs_f64(1.5));
+        assert_eq!(retry_delay(5.0, None), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn bogus_retry_after_falls_back() {
+        for bogus in ["inf", "-inf", "nan", "NaN", "soon", "Wed, 21 Oct 2015 07:28:00 GMT"] {
+            assert_eq!(retry_delay(5.0, Some(bogus)), Duration::from_secs(5), "{}", bogus);
+        }
+    }
+
+    #[test]
+    fn delays_are_clamped() {
+        assert_eq!(retry_delay(5.0, Some("-1")), Duration::ZERO);
+        assert_eq!(retry_delay(5.0, Some("1e300")), Duration::from_secs_f64(http::MAX_RETRY_AFTER));
+        assert_eq!(retry_delay(f64::NAN, Some("nan")), Duration::from_secs(5));
+    }
+}
@@ -1,10 +1,14 @@
 #[macro_use]
 extern crate tracing;
 
+#[macro_use]
+extern crate lazy_static;
+
 pub use anke_core::*;
 use dotenv;
 use std::env;
 use std::fs;
+use std::time::Duration;
 
 mod config;
 
@@ -21,20 +25,26 @@ async fn main() -> Result<()> {
     dotenv::dotenv().ok();
     setup()?;
 
-    let config = toml::from_str(
-        &fs::read_to_string(env::var("ANKE_CONFIG").unwrap_or("./anke.toml".into())).unwrap(),
-    )
-    .unwrap();
+    let config = toml::from_str(&fs::read_to_string(
+        env::var("ANKE_CONFIG").unwrap_or("./anke.toml".into()),
+    )?)?;
 
-    App::new(config)
+    App::new(config)?
         .register_aggregator_factory::<GelbooruFactory>()
         .register_aggregator_factory::<DanbooruFactory>()
         .register_filter_factory::<BlacklistFilter>()
         .register_filter_factory::<DedupeFilter>()
-        .register_filter_factory::<DiscordWebhookFilter>()
+        // before the others so they can reuse what it downloaded
         .register_filter_factory::<FilesSavingFilter>()
+        .register_filter_factory::<DiscordWebhookFilter>()
+        .register_filter_factory::<DiscordBotFilter>()
+        .register_filter_factory::<WebhookFilter>()
+        .register_filter_factory::<HydrusFilter>()
+        .register_filter_factory::<S3Filter>()
         .run()
         .await;
 
+    DiscordWebhookFilter::flush(Duration::from_secs(30)).await;
+
     Ok(())
 }